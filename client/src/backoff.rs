@@ -0,0 +1,32 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+const MAX_DELAY: Duration = Duration::from_secs(60);
+
+/// Exponential backoff with jitter, so clients don't reconnect all at once after server restart.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// Delay before next attempt, doubles each time until 60 seconds.
+    pub fn next_delay(&mut self) -> Duration {
+        let delay = INITIAL_DELAY
+            .saturating_mul(1 << self.attempt.min(16))
+            .min(MAX_DELAY);
+        self.attempt = self.attempt.saturating_add(1);
+        Self::jitter(delay)
+    }
+
+    /// Add up to 50% random delay to `base`.
+    pub fn jitter(base: Duration) -> Duration {
+        base + base.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+    }
+}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use serde::{Deserialize, Serialize};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
@@ -5,6 +7,260 @@ use tokio::io::{AsyncReadExt, AsyncWriteExt};
 pub struct Config {
     uuid: String,
     remote: Option<String>,
+    /// Seconds, triggers queued while offline older than this are dropped
+    #[serde(default = "default_queue_freshness")]
+    queue_freshness: u64,
+    #[serde(default = "default_hotkeys")]
+    hotkeys: Vec<Hotkey>,
+    #[serde(default = "default_targets")]
+    targets: Vec<Target>,
+    #[serde(default)]
+    kill: KillPolicy,
+    #[serde(default)]
+    hooks: Hooks,
+}
+
+/// Shell commands run around a kill requested by friend or hotkey, e.g. save game before
+/// and lock screen after. Output is logged.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Hooks {
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pre_kill: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    post_kill: Vec<String>,
+    /// Seconds each command may run before it is killed
+    #[serde(default = "default_hook_timeout")]
+    timeout: u64,
+}
+
+fn default_hook_timeout() -> u64 {
+    10
+}
+
+impl Hooks {
+    pub fn pre_kill(&self) -> &[String] {
+        &self.pre_kill
+    }
+
+    pub fn post_kill(&self) -> &[String] {
+        &self.post_kill
+    }
+
+    pub fn timeout(&self) -> Duration {
+        Duration::from_secs(self.timeout)
+    }
+}
+
+impl Default for Hooks {
+    fn default() -> Self {
+        Self {
+            pre_kill: vec![],
+            post_kill: vec![],
+            timeout: default_hook_timeout(),
+        }
+    }
+}
+
+/// Signal sent first and how long to wait before killing forcefully, graceful step is
+/// skipped on Windows or when `grace_period` is 0.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct KillPolicy {
+    #[serde(default = "default_kill_signal")]
+    signal: String,
+    /// Seconds
+    #[serde(default = "default_grace_period")]
+    grace_period: u64,
+    /// Also kill all descendants of matched process
+    #[serde(default)]
+    tree: bool,
+    /// Also kill parent of matched process if its name equals this, e.g. game launcher
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    launcher: Option<String>,
+    /// Seconds to wait for process to disappear after each forceful kill
+    #[serde(default = "default_verify_timeout")]
+    verify_timeout: u64,
+    /// Forceful kill attempts after the first one, before giving up
+    #[serde(default = "default_retries")]
+    retries: u32,
+    /// Seconds target keeps being killed on sight after a terminate request, 0 to disable.
+    /// Request from friend may override this.
+    #[serde(default)]
+    lockout: u64,
+}
+
+fn default_kill_signal() -> String {
+    "SIGTERM".to_string()
+}
+
+fn default_grace_period() -> u64 {
+    5
+}
+
+fn default_verify_timeout() -> u64 {
+    2
+}
+
+fn default_retries() -> u32 {
+    2
+}
+
+impl KillPolicy {
+    pub fn signal(&self) -> &str {
+        &self.signal
+    }
+
+    pub fn grace_period(&self) -> Duration {
+        Duration::from_secs(self.grace_period)
+    }
+
+    pub fn tree(&self) -> bool {
+        self.tree
+    }
+
+    pub fn launcher(&self) -> Option<&str> {
+        self.launcher.as_deref()
+    }
+
+    pub fn verify_timeout(&self) -> Duration {
+        Duration::from_secs(self.verify_timeout)
+    }
+
+    pub fn retries(&self) -> u32 {
+        self.retries
+    }
+
+    pub fn lockout(&self) -> u64 {
+        self.lockout
+    }
+}
+
+impl Default for KillPolicy {
+    fn default() -> Self {
+        Self {
+            signal: default_kill_signal(),
+            grace_period: default_grace_period(),
+            tree: false,
+            launcher: None,
+            verify_timeout: default_verify_timeout(),
+            retries: default_retries(),
+            lockout: 0,
+        }
+    }
+}
+
+/// Process to kill, every field set must match.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Target {
+    /// Exact process name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    /// Process name with `*` and `?` wildcard
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_glob: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name_regex: Option<String>,
+    /// Full path of executable
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    exe: Option<String>,
+    /// Substring of command line
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    cmdline: Option<String>,
+    /// Owner of process
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    user: Option<String>,
+}
+
+impl Target {
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    pub fn name_glob(&self) -> Option<&str> {
+        self.name_glob.as_deref()
+    }
+
+    pub fn name_regex(&self) -> Option<&str> {
+        self.name_regex.as_deref()
+    }
+
+    pub fn exe(&self) -> Option<&str> {
+        self.exe.as_deref()
+    }
+
+    pub fn cmdline(&self) -> Option<&str> {
+        self.cmdline.as_deref()
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+}
+
+/// Builds made with `B7_TASK_TO_KILL` keep working without `targets` in configure.
+fn default_targets() -> Vec<Target> {
+    option_env!("B7_TASK_TO_KILL")
+        .map(|name| Target {
+            name: Some(name.to_string()),
+            ..Default::default()
+        })
+        .into_iter()
+        .collect()
+}
+
+/// Hotkey binding, e.g. `{ key = "ctrl+shift+F9", action = "terminate", friend = "bob" }`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Hotkey {
+    key: String,
+    action: Action,
+    /// User name or uuid on server, not used by `cancel`, everyone if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    friend: Option<String>,
+    /// Seconds, only used by `terminate`, receiver's `kill.lockout` if omitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    lockout: Option<u64>,
+}
+
+impl Hotkey {
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    pub fn friend(&self) -> Option<&str> {
+        self.friend.as_deref()
+    }
+
+    pub fn lockout(&self) -> Option<u64> {
+        self.lockout
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Action {
+    Terminate,
+    Poke,
+    /// Drop own queued terminate requests and lift the lockout they set, on friends too
+    Cancel,
+    ToggleDoNotDisturb,
+    /// Lift lockout early
+    AllClear,
+}
+
+fn default_hotkeys() -> Vec<Hotkey> {
+    vec![Hotkey {
+        key: "ctrl+F6".to_string(),
+        action: Action::Terminate,
+        friend: None,
+        lockout: None,
+    }]
+}
+
+fn default_queue_freshness() -> u64 {
+    60
 }
 
 impl Config {
@@ -21,6 +277,7 @@ impl Config {
 
     pub async fn write(&self, file: &str) -> anyhow::Result<()> {
         let mut f = tokio::fs::OpenOptions::new()
+            .write(true)
             .create(true)
             .truncate(true)
             .open(file)
@@ -36,8 +293,32 @@ impl Config {
         &self.uuid
     }
 
+    pub fn set_uuid(&mut self, uuid: String) {
+        self.uuid = uuid;
+    }
+
     pub fn remote(&self) -> Option<&str> {
-        self.remote.as_ref().map(|x| x.as_str())
+        self.remote.as_deref()
+    }
+
+    pub fn queue_freshness(&self) -> Duration {
+        Duration::from_secs(self.queue_freshness)
+    }
+
+    pub fn hotkeys(&self) -> &[Hotkey] {
+        &self.hotkeys
+    }
+
+    pub fn targets(&self) -> &[Target] {
+        &self.targets
+    }
+
+    pub fn kill(&self) -> &KillPolicy {
+        &self.kill
+    }
+
+    pub fn hooks(&self) -> &Hooks {
+        &self.hooks
     }
 }
 
@@ -46,6 +327,11 @@ impl Default for Config {
         Self {
             uuid: uuid::Uuid::new_v4().to_string(),
             remote: None,
+            queue_freshness: default_queue_freshness(),
+            hotkeys: default_hotkeys(),
+            targets: default_targets(),
+            kill: KillPolicy::default(),
+            hooks: Hooks::default(),
         }
     }
 }
@@ -0,0 +1,125 @@
+use std::{
+    io::{BufRead, BufReader, Read},
+    process::{Command, Stdio},
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use log::{error, info, log, warn, Level};
+
+use crate::config::Hooks;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long to wait for hook output after the hook exited.
+const DRAIN_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// Run pre-kill hooks, `kill`, then post-kill hooks, blocking.
+pub fn around_kill<T>(hooks: &Hooks, kill: impl FnOnce() -> T) -> T {
+    for command in hooks.pre_kill() {
+        run("pre-kill", command, hooks.timeout());
+    }
+    let ret = kill();
+    for command in hooks.post_kill() {
+        run("post-kill", command, hooks.timeout());
+    }
+    ret
+}
+
+fn shell(command: &str) -> Command {
+    #[cfg(windows)]
+    {
+        let mut shell = Command::new("cmd");
+        shell.args(["/C", command]);
+        shell
+    }
+    #[cfg(not(windows))]
+    {
+        let mut shell = Command::new("sh");
+        shell.args(["-c", command]);
+        shell
+    }
+}
+
+/// Log lines in background as they arrive, so a chatty hook never blocks on full pipe
+/// and output of a hung hook is kept.
+fn forward(
+    stage: &str,
+    pipe: Option<impl Read + Send + 'static>,
+    level: Level,
+) -> Option<JoinHandle<()>> {
+    let pipe = pipe?;
+    let stage = stage.to_string();
+    Some(std::thread::spawn(move || {
+        for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+            log!(level, "[{stage}] {line}");
+        }
+    }))
+}
+
+fn run(stage: &str, command: &str, timeout: Duration) {
+    info!("Run {stage} hook: {command}");
+    let mut child = match shell(command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+    {
+        Ok(child) => child,
+        Err(e) => {
+            error!("Unable to start {stage} hook {command:?}: {e}");
+            return;
+        }
+    };
+    let stdout = forward(stage, child.stdout.take(), Level::Info);
+    let stderr = forward(stage, child.stderr.take(), Level::Warn);
+
+    let deadline = Instant::now() + timeout;
+    let status = loop {
+        match child.try_wait() {
+            Ok(Some(status)) => break Some(status),
+            Ok(None) if Instant::now() >= deadline => {
+                warn!("Hook {stage} {command:?} timed out after {timeout:?}, kill it");
+                child.kill().ok();
+                child.wait().ok();
+                break None;
+            }
+            Ok(None) => std::thread::sleep(POLL_INTERVAL),
+            Err(e) => {
+                error!("Wait {stage} hook {command:?} error: {e}");
+                break None;
+            }
+        }
+    };
+
+    // Background processes started by the hook keep pipes open, readers are left to them
+    // after a short wait for output still in flight
+    let readers = [stdout, stderr].into_iter().flatten().collect::<Vec<_>>();
+    let deadline = Instant::now() + DRAIN_TIMEOUT;
+    while readers.iter().any(|h| !h.is_finished()) && Instant::now() < deadline {
+        std::thread::sleep(POLL_INTERVAL);
+    }
+    match status {
+        Some(status) if status.success() => info!("Hook {stage} finished"),
+        Some(status) => warn!("Hook {stage} {command:?} exited with {status}"),
+        None => {}
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn background_process_does_not_block() {
+        let started = Instant::now();
+        run("pre-kill", "sleep 5 & echo started", Duration::from_secs(3));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn timeout() {
+        let started = Instant::now();
+        run("pre-kill", "sleep 5", Duration::from_millis(300));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+}
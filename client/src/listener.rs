@@ -1,45 +1,85 @@
 use std::{
+    collections::HashMap,
+    str::FromStr,
     sync::{atomic::AtomicBool, Arc},
     thread::JoinHandle,
     time::Duration,
 };
 
-use global_hotkey::{
-    hotkey::{Code, HotKey, Modifiers},
-    GlobalHotKeyEvent, GlobalHotKeyManager,
-};
-use once_cell::sync::Lazy;
+use anyhow::anyhow;
+use global_hotkey::{hotkey::HotKey, GlobalHotKeyEvent, GlobalHotKeyManager, HotKeyState};
 use tap::TapFallible;
 use tokio::sync::mpsc;
 
-static HOT_KEY: Lazy<HotKey> = Lazy::new(|| HotKey::new(Some(Modifiers::CONTROL), Code::F6));
-
-use crate::web::WebEvent;
+use crate::{
+    config::{Action, Hotkey},
+    web::WebEvent,
+};
 
 pub struct KeyShortcut {
     handler: JoinHandle<anyhow::Result<()>>,
     manager: GlobalHotKeyManager,
+    hotkeys: Vec<HotKey>,
 }
 
 impl KeyShortcut {
     pub fn start(
+        bindings: &[Hotkey],
         sender: mpsc::Sender<WebEvent>,
         stop_signal: Arc<AtomicBool>,
     ) -> anyhow::Result<Self> {
         let manager = GlobalHotKeyManager::new().unwrap();
-        //let hotkey = HotKey::new(Some(Modifiers::SHIFT), Code::KeyD);
-        manager.register(*HOT_KEY)?;
+
+        let mut hotkeys = vec![];
+        let mut actions = HashMap::new();
+        for binding in bindings {
+            let hotkey = HotKey::from_str(binding.key())
+                .map_err(|e| anyhow!("Invalid hotkey {:?}: {e}", binding.key()))?;
+            manager
+                .register(hotkey)
+                .map_err(|e| anyhow!("Unable register hotkey {:?}: {e}", binding.key()))?;
+            log::debug!("Register {} as {:?}", binding.key(), binding.action());
+            hotkeys.push(hotkey);
+            actions.insert(hotkey.id(), Self::event(binding));
+        }
 
         Ok(Self {
-            handler: std::thread::spawn(|| Self::run(sender, stop_signal)),
+            handler: std::thread::spawn(|| Self::run(actions, sender, stop_signal)),
             manager,
+            hotkeys,
         })
     }
-    fn run(sender: mpsc::Sender<WebEvent>, stop_signal: Arc<AtomicBool>) -> anyhow::Result<()> {
+
+    fn event(binding: &Hotkey) -> WebEvent {
+        let friend = binding.friend().map(ToString::to_string);
+        match binding.action() {
+            Action::Terminate => WebEvent::Terminate {
+                friend,
+                lockout: binding.lockout(),
+            },
+            Action::Poke => WebEvent::Poke { friend },
+            Action::Cancel => WebEvent::Cancel,
+            Action::ToggleDoNotDisturb => WebEvent::ToggleDoNotDisturb,
+            Action::AllClear => WebEvent::AllClear { friend },
+        }
+    }
+
+    fn run(
+        actions: HashMap<u32, WebEvent>,
+        sender: mpsc::Sender<WebEvent>,
+        stop_signal: Arc<AtomicBool>,
+    ) -> anyhow::Result<()> {
         loop {
-            while let Ok(_) = GlobalHotKeyEvent::receiver().recv_timeout(Duration::from_secs(1)) {
+            while let Ok(event) = GlobalHotKeyEvent::receiver().recv_timeout(Duration::from_secs(1))
+            {
+                if event.state() != HotKeyState::Pressed {
+                    continue;
+                }
+                let Some(action) = actions.get(&event.id()) else {
+                    continue;
+                };
                 sender
-                    .blocking_send(WebEvent::SendTerminate)
+                    .blocking_send(action.clone())
                     .tap_err(|_| log::error!("Fail to send message to web thread"))
                     .ok();
             }
@@ -58,12 +98,11 @@ impl KeyShortcut {
             }
         }
         self.manager
-            .unregister(*HOT_KEY)
+            .unregister_all(&self.hotkeys)
             .tap_err(|e| log::error!("Error unregister key {e:?}"))?;
-        Ok(if self.handler.is_finished() {
-            self.handler.join().unwrap()?
-        } else {
-            ()
-        })
+        if self.handler.is_finished() {
+            self.handler.join().unwrap()?;
+        }
+        Ok(())
     }
 }
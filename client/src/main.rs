@@ -1,5 +1,8 @@
+mod backoff;
 mod config;
+mod hook;
 mod listener;
+mod remote;
 mod task;
 mod web;
 
@@ -9,13 +12,16 @@ use listener::KeyShortcut;
 use std::{
     io::Write,
     sync::{atomic::AtomicBool, Arc},
+    time::Duration,
 };
 use tap::TapFallible;
+use task::{Killer, MatcherSet, ProcessMonitor};
 use tokio::sync::mpsc;
 use web::{make_connection, WebEvent};
 
-const REMOTE_ADDRESS: &str = env!("B7_REMOTE");
-const TERMINATE_TARGET: &str = env!("B7_TASK_TO_KILL");
+/// Fallback server address for builds made with `B7_REMOTE`.
+const REMOTE_ADDRESS: Option<&str> = option_env!("B7_REMOTE");
+const DISCOVER_TIMEOUT: Duration = Duration::from_secs(10);
 
 async fn load_config(config: String) -> anyhow::Result<Config> {
     Ok(if !Config::exists(&config) {
@@ -31,34 +37,68 @@ async fn load_config(config: String) -> anyhow::Result<Config> {
     })
 }
 
-async fn async_main(config: String) -> anyhow::Result<()> {
-    let cfg = load_config(config).await?;
+/// Server address from command line, environment, configure file, then build time,
+/// discover on local network if none of them set or `discover` requested.
+async fn resolve_remote(
+    cfg: &Config,
+    remote: Option<String>,
+    discover: bool,
+) -> anyhow::Result<String> {
+    let address = remote
+        .or_else(|| std::env::var("B7_REMOTE").ok())
+        .or_else(|| cfg.remote().map(ToString::to_string))
+        .or_else(|| REMOTE_ADDRESS.map(ToString::to_string));
+    match address {
+        Some(address) if !discover => remote::normalize(&address),
+        _ => remote::discover(DISCOVER_TIMEOUT).await,
+    }
+}
+
+async fn async_main(
+    config: String,
+    invite: Option<String>,
+    remote: Option<String>,
+    discover: bool,
+) -> anyhow::Result<()> {
+    let cfg = load_config(config.clone()).await?;
 
     let exit_signal = Arc::new(AtomicBool::new(false));
     let (sender, receiver) = mpsc::channel(64);
-    let uuid = cfg.uuid().to_string();
-    let remote = cfg.remote().unwrap_or(REMOTE_ADDRESS).to_string();
+    let remote = resolve_remote(&cfg, remote, discover).await?;
+    let killer = Arc::new(Killer::new(cfg.targets(), cfg.kill())?);
+    if !killer.has_targets() {
+        log::warn!("No target configured, terminate request will kill nothing");
+    }
+
+    let monitor = ProcessMonitor::start(MatcherSet::new(cfg.targets())?);
 
-    let keyboard_thread = KeyShortcut::start(sender.clone(), exit_signal.clone())?;
+    let keyboard_thread = KeyShortcut::start(cfg.hotkeys(), sender.clone(), exit_signal.clone())?;
 
-    let connection = tokio::spawn(make_connection(remote, uuid, receiver));
+    let connection = tokio::spawn(make_connection(
+        remote,
+        cfg,
+        config,
+        invite,
+        killer,
+        monitor.subscribe(),
+        receiver,
+    ));
 
     tokio::select! {
-            _ = async {
-                tokio::signal::ctrl_c().await.ok();
-                sender.send(WebEvent::Stop).await.ok();
-                exit_signal.store(true, std::sync::atomic::Ordering::Relaxed);
-                tokio::signal::ctrl_c().await.ok();
-            } => {
-    x
-            }
-
-            ret = connection => {
-                ret??;
-            }
+        _ = async {
+            tokio::signal::ctrl_c().await.ok();
+            sender.send(WebEvent::Stop).await.ok();
+            exit_signal.store(true, std::sync::atomic::Ordering::Relaxed);
+            tokio::signal::ctrl_c().await.ok();
+        } => {}
+
+        ret = connection => {
+            ret??;
         }
+    }
 
     keyboard_thread.wait()?;
+    monitor.stop();
 
     Ok(())
 }
@@ -80,6 +120,9 @@ fn main() -> anyhow::Result<()> {
         .args(&[
             arg!([CONFIG] "Configure file").default_value("config.toml"),
             arg!(--systemd "Disable time output in log"),
+            arg!(--invite <CODE> "Invite code to enrol with the server"),
+            arg!(--remote <URL> "Server address, e.g. https://example.com or host:port"),
+            arg!(--discover "Find server on local network"),
         ])
         .get_matches();
 
@@ -91,5 +134,8 @@ fn main() -> anyhow::Result<()> {
         .unwrap()
         .block_on(async_main(
             matches.get_one::<String>("CONFIG").unwrap().to_string(),
+            matches.get_one::<String>("invite").cloned(),
+            matches.get_one::<String>("remote").cloned(),
+            matches.get_flag("discover"),
         ))
 }
@@ -0,0 +1,132 @@
+use std::{net::IpAddr, time::Duration};
+
+use anyhow::anyhow;
+use log::{debug, info};
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use reqwest::Url;
+
+/// Must match the service type advertised by `firendo-host`.
+const SERVICE_TYPE: &str = "_firendo._tcp.local.";
+const WEBSOCKET_PATH: &str = "/ws/";
+
+/// Turn `http(s)://host`, `ws(s)://host` or bare `host:port` into the websocket endpoint.
+pub fn normalize(address: &str) -> anyhow::Result<String> {
+    let address = address.trim();
+    let mut url = match Url::parse(address) {
+        Ok(url) if url.has_host() => url,
+        _ => Url::parse(&format!("ws://{address}"))
+            .map_err(|e| anyhow!("Invalid server address {address:?}: {e}"))?,
+    };
+    let scheme = match url.scheme() {
+        "http" | "ws" => "ws",
+        "https" | "wss" => "wss",
+        scheme => return Err(anyhow!("Unsupported scheme {scheme:?} in {address:?}")),
+    };
+    url.set_scheme(scheme)
+        .map_err(|_| anyhow!("Unable to use {scheme} for {address:?}"))?;
+    if url.path().is_empty() || url.path() == "/" {
+        url.set_path(WEBSOCKET_PATH);
+    }
+    Ok(url.to_string())
+}
+
+/// Find a server on LAN through mDNS/DNS-SD.
+pub async fn discover(timeout: Duration) -> anyhow::Result<String> {
+    let daemon = ServiceDaemon::new()?;
+    let receiver = daemon.browse(SERVICE_TYPE)?;
+    info!("Looking for server on local network");
+
+    let ret = tokio::time::timeout(timeout, async {
+        while let Ok(event) = receiver.recv_async().await {
+            let ServiceEvent::ServiceResolved(service) = event else {
+                continue;
+            };
+            debug!("Found {} at {:?}", service.fullname, service.addresses);
+            // Link-local IPv6 is unusable without scope id
+            let Some(ip) = service
+                .addresses
+                .iter()
+                .map(|ip| ip.to_ip_addr())
+                .filter(|ip| !matches!(ip, IpAddr::V6(v6) if v6.is_unicast_link_local()))
+                .min_by_key(|ip| ip.is_ipv6())
+            else {
+                continue;
+            };
+            let path = service
+                .get_property_val_str("path")
+                .unwrap_or(WEBSOCKET_PATH);
+            let host = match ip {
+                IpAddr::V4(ip) => ip.to_string(),
+                IpAddr::V6(ip) => format!("[{ip}]"),
+            };
+            return Ok(format!("ws://{host}:{}{path}", service.port));
+        }
+        Err(anyhow!("mDNS browser stopped"))
+    })
+    .await
+    .map_err(|_| anyhow!("No server found on local network in {timeout:?}"))?;
+
+    daemon.shutdown().ok();
+    let remote = ret?;
+    info!("Discovered server {remote}");
+    Ok(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_scheme() {
+        assert_eq!(
+            normalize("http://example.com").unwrap(),
+            "ws://example.com/ws/"
+        );
+        assert_eq!(
+            normalize("https://example.com").unwrap(),
+            "wss://example.com/ws/"
+        );
+        assert_eq!(
+            normalize("ws://example.com/").unwrap(),
+            "ws://example.com/ws/"
+        );
+        assert_eq!(
+            normalize("WSS://example.com").unwrap(),
+            "wss://example.com/ws/"
+        );
+        assert!(normalize("ftp://example.com").is_err());
+    }
+
+    #[test]
+    fn normalize_bare_host() {
+        assert_eq!(
+            normalize("192.168.1.2:3000").unwrap(),
+            "ws://192.168.1.2:3000/ws/"
+        );
+        assert_eq!(
+            normalize(" localhost:3000 ").unwrap(),
+            "ws://localhost:3000/ws/"
+        );
+        assert_eq!(normalize("[::1]:3000").unwrap(), "ws://[::1]:3000/ws/");
+        assert_eq!(normalize("example.com").unwrap(), "ws://example.com/ws/");
+        assert!(normalize("").is_err());
+        assert!(normalize("host:port").is_err());
+    }
+
+    #[test]
+    fn normalize_keeps_path() {
+        assert_eq!(
+            normalize("https://example.com:8443/friendo/ws/").unwrap(),
+            "wss://example.com:8443/friendo/ws/"
+        );
+        assert_eq!(
+            normalize("example.com/custom?token=1").unwrap(),
+            "ws://example.com/custom?token=1"
+        );
+        // Default port is dropped
+        assert_eq!(
+            normalize("https://example.com:443").unwrap(),
+            "wss://example.com/ws/"
+        );
+    }
+}
@@ -0,0 +1,317 @@
+use std::{
+    io,
+    os::fd::{AsRawFd, FromRawFd, OwnedFd},
+};
+
+use sysinfo::Signal;
+
+use super::unix::signal_number;
+
+/// Process file descriptor, keeps referring to the same process even after its pid is reused.
+pub(crate) struct Pidfd(OwnedFd);
+
+impl Pidfd {
+    pub(crate) fn open(pid: u32) -> io::Result<Self> {
+        // SAFETY: pidfd_open takes no pointers, returned fd is owned by us
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid as libc::pid_t, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) }))
+    }
+
+    pub(crate) fn send_signal(&self, signal: Signal) -> io::Result<()> {
+        let signal =
+            signal_number(signal).ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+        let ret = unsafe {
+            libc::syscall(
+                libc::SYS_pidfd_send_signal,
+                self.0.as_raw_fd(),
+                signal,
+                std::ptr::null::<libc::siginfo_t>(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Pidfd becomes readable once the process exited, zombie included.
+    pub(crate) fn exited(&self) -> bool {
+        let mut fd = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        unsafe { libc::poll(&mut fd, 1, 0) > 0 }
+    }
+}
+
+// linux/connector.h and linux/cn_proc.h
+const CN_IDX_PROC: u32 = 1;
+const CN_VAL_PROC: u32 = 1;
+const PROC_CN_MCAST_LISTEN: u32 = 1;
+const PROC_EVENT_EXEC: u32 = 0x0000_0002;
+const PROC_EVENT_COMM: u32 = 0x0000_0200;
+const PROC_EVENT_EXIT: u32 = 0x8000_0000;
+
+const NLMSG_HEADER_LEN: usize = 16;
+const CN_MSG_LEN: usize = 20;
+/// `what`, `cpu` and `timestamp_ns` before event data in `struct proc_event`
+const PROC_EVENT_HEADER_LEN: usize = 16;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ProcEvent {
+    /// Process image or name changed, pid is thread group id
+    Exec(u32),
+    Exit(u32),
+    /// Kernel dropped messages, full rescan needed
+    Overrun,
+}
+
+/// Netlink proc connector, receives exec and exit of every process. Requires `CAP_NET_ADMIN`.
+pub(crate) struct ProcConnector(OwnedFd);
+
+impl ProcConnector {
+    pub(crate) fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC,
+                libc::NETLINK_CONNECTOR,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut address: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        address.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        address.nl_groups = CN_IDX_PROC;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &address as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // Wake up periodically so caller can check stop signal
+        let timeout = libc::timeval {
+            tv_sec: 1,
+            tv_usec: 0,
+        };
+        let ret = unsafe {
+            libc::setsockopt(
+                fd.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let connector = Self(fd);
+        connector.listen()?;
+        Ok(connector)
+    }
+
+    fn listen(&self) -> io::Result<()> {
+        let total = NLMSG_HEADER_LEN + CN_MSG_LEN + 4;
+        let mut message = Vec::with_capacity(total);
+        // nlmsghdr
+        message.extend((total as u32).to_ne_bytes());
+        message.extend((libc::NLMSG_DONE as u16).to_ne_bytes());
+        message.extend(0u16.to_ne_bytes());
+        message.extend(0u32.to_ne_bytes());
+        message.extend(std::process::id().to_ne_bytes());
+        // cn_msg
+        message.extend(CN_IDX_PROC.to_ne_bytes());
+        message.extend(CN_VAL_PROC.to_ne_bytes());
+        message.extend(0u32.to_ne_bytes());
+        message.extend(0u32.to_ne_bytes());
+        message.extend(4u16.to_ne_bytes());
+        message.extend(0u16.to_ne_bytes());
+        // proc_cn_mcast_op
+        message.extend(PROC_CN_MCAST_LISTEN.to_ne_bytes());
+
+        let ret = unsafe {
+            libc::send(
+                self.0.as_raw_fd(),
+                message.as_ptr() as *const libc::c_void,
+                message.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Wait up to one second for events.
+    pub(crate) fn recv(&self) -> io::Result<Vec<ProcEvent>> {
+        let mut buffer = [0u8; 8192];
+        let size = unsafe {
+            libc::recv(
+                self.0.as_raw_fd(),
+                buffer.as_mut_ptr() as *mut libc::c_void,
+                buffer.len(),
+                0,
+            )
+        };
+        if size < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                Some(libc::EAGAIN | libc::EINTR) => Ok(vec![]),
+                Some(libc::ENOBUFS) => Ok(vec![ProcEvent::Overrun]),
+                _ => Err(e),
+            };
+        }
+        Ok(parse(&buffer[..size as usize]))
+    }
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(
+        buffer.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+fn parse(mut buffer: &[u8]) -> Vec<ProcEvent> {
+    let mut events = vec![];
+    while let Some(length) = read_u32(buffer, 0) {
+        let length = length as usize;
+        if length < NLMSG_HEADER_LEN || length > buffer.len() {
+            break;
+        }
+        let message = &buffer[..length];
+        let kind = u16::from_ne_bytes([message[4], message[5]]) as libc::c_int;
+        if kind == libc::NLMSG_OVERRUN {
+            events.push(ProcEvent::Overrun);
+        } else if kind == libc::NLMSG_DONE {
+            let event = NLMSG_HEADER_LEN + CN_MSG_LEN;
+            let data = event + PROC_EVENT_HEADER_LEN;
+            // Event data starts with pid then tgid, only process itself matters, not its threads
+            let event = read_u32(message, event)
+                .zip(read_u32(message, data))
+                .zip(read_u32(message, data + 4));
+            match event {
+                Some(((PROC_EVENT_EXEC | PROC_EVENT_COMM, _), tgid)) => {
+                    events.push(ProcEvent::Exec(tgid))
+                }
+                Some(((PROC_EVENT_EXIT, pid), tgid)) if pid == tgid => {
+                    events.push(ProcEvent::Exit(tgid))
+                }
+                _ => {}
+            }
+        }
+        // NLMSG_ALIGN
+        let next = (length + 3) & !3;
+        buffer = buffer.get(next..).unwrap_or_default();
+    }
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// One netlink message carrying a proc event, `data` follows `pid` and `tgid`.
+    fn message(kind: libc::c_int, what: u32, pid: u32, tgid: u32, data: usize) -> Vec<u8> {
+        let length = NLMSG_HEADER_LEN + CN_MSG_LEN + PROC_EVENT_HEADER_LEN + 8 + data;
+        let mut buffer = vec![];
+        buffer.extend((length as u32).to_ne_bytes());
+        buffer.extend((kind as u16).to_ne_bytes());
+        buffer.resize(NLMSG_HEADER_LEN, 0);
+        buffer.extend(CN_IDX_PROC.to_ne_bytes());
+        buffer.extend(CN_VAL_PROC.to_ne_bytes());
+        buffer.resize(NLMSG_HEADER_LEN + CN_MSG_LEN, 0);
+        buffer.extend(what.to_ne_bytes());
+        buffer.resize(NLMSG_HEADER_LEN + CN_MSG_LEN + PROC_EVENT_HEADER_LEN, 0);
+        buffer.extend(pid.to_ne_bytes());
+        buffer.extend(tgid.to_ne_bytes());
+        buffer.resize(length, 0);
+        buffer
+    }
+
+    fn aligned(mut message: Vec<u8>) -> Vec<u8> {
+        message.resize((message.len() + 3) & !3, 0);
+        message
+    }
+
+    #[test]
+    fn exec_and_exit() {
+        let done = libc::NLMSG_DONE;
+        let mut buffer = message(done, PROC_EVENT_EXEC, 100, 100, 0);
+        buffer.extend(message(done, PROC_EVENT_COMM, 101, 100, 16));
+        buffer.extend(message(done, PROC_EVENT_EXIT, 100, 100, 16));
+        assert_eq!(
+            parse(&buffer),
+            [
+                ProcEvent::Exec(100),
+                ProcEvent::Exec(100),
+                ProcEvent::Exit(100)
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_thread_and_other_events() {
+        let done = libc::NLMSG_DONE;
+        // Thread exit, process keeps running
+        let mut buffer = message(done, PROC_EVENT_EXIT, 101, 100, 16);
+        // Fork
+        buffer.extend(message(done, 0x0000_0001, 100, 100, 8));
+        buffer.extend(message(libc::NLMSG_NOOP, PROC_EVENT_EXEC, 100, 100, 0));
+        assert_eq!(parse(&buffer), []);
+    }
+
+    #[test]
+    fn overrun() {
+        let mut buffer = message(libc::NLMSG_OVERRUN, 0, 0, 0, 0);
+        buffer.extend(message(libc::NLMSG_DONE, PROC_EVENT_EXEC, 7, 7, 0));
+        assert_eq!(parse(&buffer), [ProcEvent::Overrun, ProcEvent::Exec(7)]);
+    }
+
+    #[test]
+    fn alignment() {
+        let mut buffer = aligned(message(libc::NLMSG_DONE, PROC_EVENT_EXEC, 1, 1, 2));
+        buffer.extend(message(libc::NLMSG_DONE, PROC_EVENT_EXEC, 2, 2, 0));
+        assert_eq!(parse(&buffer), [ProcEvent::Exec(1), ProcEvent::Exec(2)]);
+    }
+
+    #[test]
+    fn truncated() {
+        let done = libc::NLMSG_DONE;
+        let message = message(done, PROC_EVENT_EXEC, 1, 1, 0);
+        assert_eq!(parse(&[]), []);
+        assert_eq!(parse(&message[..3]), []);
+        assert_eq!(parse(&message[..message.len() - 1]), []);
+
+        // Header claims less than a netlink header
+        let mut buffer = message.clone();
+        buffer[..4].copy_from_slice(&8u32.to_ne_bytes());
+        assert_eq!(parse(&buffer), []);
+
+        // Length fits but event data is cut short
+        let length = NLMSG_HEADER_LEN + CN_MSG_LEN + 4;
+        let mut buffer = message[..length].to_vec();
+        buffer[..4].copy_from_slice(&(length as u32).to_ne_bytes());
+        assert_eq!(parse(&buffer), []);
+
+        // Complete message before a truncated one is kept
+        let mut buffer = message.clone();
+        buffer.extend(&message[..20]);
+        assert_eq!(parse(&buffer), [ProcEvent::Exec(1)]);
+    }
+}
@@ -0,0 +1,89 @@
+use std::{sync::Arc, time::Duration};
+
+use log::{info, warn};
+use tokio::{
+    sync::{broadcast, watch},
+    time::Instant,
+};
+
+use super::{Killer, ProcessEvent};
+
+/// Keep killing targets on sight until the lockout ends or is lifted.
+pub struct Lockout {
+    until: watch::Sender<Option<Instant>>,
+}
+
+impl Lockout {
+    /// Must be called inside tokio runtime, watcher exits once `Lockout` dropped.
+    pub fn start(killer: Arc<Killer>, events: broadcast::Receiver<ProcessEvent>) -> Self {
+        let (until, receiver) = watch::channel(None);
+        tokio::spawn(Self::run(killer, receiver, events));
+        Self { until }
+    }
+
+    /// Lock out for at least `duration` from now, a longer running lockout is kept.
+    ///
+    /// Returns whether the deadline moved.
+    pub fn extend(&self, duration: Duration) -> bool {
+        let deadline = Instant::now() + duration;
+        let extended = self.until.send_if_modified(|until| match until {
+            Some(until) if *until >= deadline => false,
+            _ => {
+                until.replace(deadline);
+                true
+            }
+        });
+        if extended {
+            info!("Lockout for {duration:?}");
+        }
+        extended
+    }
+
+    pub fn lift(&self) {
+        let active = self
+            .until
+            .borrow()
+            .is_some_and(|until| until > Instant::now());
+        self.until.send_replace(None);
+        if active {
+            info!("Lockout lifted");
+        }
+    }
+
+    async fn run(
+        killer: Arc<Killer>,
+        mut receiver: watch::Receiver<Option<Instant>>,
+        mut events: broadcast::Receiver<ProcessEvent>,
+    ) {
+        loop {
+            let until = receiver
+                .borrow_and_update()
+                .filter(|until| *until > Instant::now());
+            tokio::select! {
+                ret = receiver.changed() => if ret.is_err() {
+                    break;
+                },
+                _ = tokio::time::sleep_until(until.unwrap_or_else(Instant::now)), if until.is_some() => {
+                    info!("Lockout ended");
+                }
+                event = events.recv() => {
+                    let relaunched = match event {
+                        Ok(ProcessEvent::Started { .. }) => true,
+                        Ok(ProcessEvent::Exited { .. }) => false,
+                        // Missed events may include a start
+                        Err(broadcast::error::RecvError::Lagged(_)) => true,
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    };
+                    if !relaunched || until.is_none() {
+                        continue;
+                    }
+                    warn!("Target relaunched during lockout, kill again");
+                    let killer = killer.clone();
+                    tokio::task::spawn_blocking(move || unsafe { killer.kill() })
+                        .await
+                        .ok();
+                }
+            }
+        }
+    }
+}
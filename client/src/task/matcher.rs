@@ -0,0 +1,152 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use regex::Regex;
+use sysinfo::{Process, Users};
+
+use crate::config::Target;
+
+/// Compiled form of `Target`, every field set must match.
+#[derive(Debug)]
+struct Matcher {
+    name: Option<String>,
+    name_pattern: Option<Regex>,
+    exe: Option<String>,
+    cmdline: Option<String>,
+    user: Option<String>,
+}
+
+impl Matcher {
+    fn new(target: &Target) -> anyhow::Result<Self> {
+        let name_pattern = match (target.name_glob(), target.name_regex()) {
+            (Some(_), Some(_)) => {
+                return Err(anyhow!("Only one of name_glob and name_regex allowed"))
+            }
+            (Some(glob), None) => Some(glob_to_regex(glob)?),
+            (None, Some(regex)) => Some(Regex::new(regex)?),
+            (None, None) => None,
+        };
+        let ret = Self {
+            name: target.name().map(ToString::to_string),
+            name_pattern,
+            exe: target.exe().map(ToString::to_string),
+            cmdline: target.cmdline().map(ToString::to_string),
+            user: target.user().map(ToString::to_string),
+        };
+        if ret.name.is_none()
+            && ret.name_pattern.is_none()
+            && ret.exe.is_none()
+            && ret.cmdline.is_none()
+        {
+            return Err(anyhow!(
+                "Target should set at least one of name, name_glob, name_regex, exe or cmdline"
+            ));
+        }
+        Ok(ret)
+    }
+
+    fn matches(&self, process: &Process, users: &Users) -> bool {
+        if self
+            .name
+            .as_ref()
+            .is_some_and(|name| process.name().ne(name))
+        {
+            return false;
+        }
+        if self
+            .name_pattern
+            .as_ref()
+            .is_some_and(|pattern| !pattern.is_match(process.name()))
+        {
+            return false;
+        }
+        if self
+            .exe
+            .as_ref()
+            .is_some_and(|exe| process.exe() != Some(Path::new(exe)))
+        {
+            return false;
+        }
+        if self
+            .cmdline
+            .as_ref()
+            .is_some_and(|cmdline| !process.cmd().join(" ").contains(cmdline.as_str()))
+        {
+            return false;
+        }
+        if let Some(ref user) = self.user {
+            let owner = process
+                .user_id()
+                .and_then(|uid| users.get_user_by_id(uid))
+                .map(|u| u.name());
+            if owner != Some(user.as_str()) {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// `*` and `?` wildcard, whole name should match.
+fn glob_to_regex(glob: &str) -> anyhow::Result<Regex> {
+    let pattern = regex::escape(glob).replace(r"\*", ".*").replace(r"\?", ".");
+    Ok(Regex::new(&format!("^{pattern}$"))?)
+}
+
+/// Processes matching any of the targets get killed.
+#[derive(Debug)]
+pub struct MatcherSet(Vec<Matcher>);
+
+impl MatcherSet {
+    pub fn new(targets: &[Target]) -> anyhow::Result<Self> {
+        targets
+            .iter()
+            .map(|target| {
+                Matcher::new(target).map_err(|e| anyhow!("Invalid target {target:?}: {e}"))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Self)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn matches(&self, process: &Process, users: &Users) -> bool {
+        self.0.iter().any(|m| m.matches(process, users))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        let pattern = glob_to_regex("game*.exe").unwrap();
+        assert!(pattern.is_match("game.exe"));
+        assert!(pattern.is_match("game-win64.exe"));
+        assert!(!pattern.is_match("gameXexe"));
+        assert!(!pattern.is_match("launcher-game.exe"));
+        assert!(!pattern.is_match("game.exe.bak"));
+
+        let pattern = glob_to_regex("proc?").unwrap();
+        assert!(pattern.is_match("proc1"));
+        assert!(!pattern.is_match("proc"));
+        assert!(!pattern.is_match("proc12"));
+
+        // Regex syntax in glob is literal
+        let pattern = glob_to_regex("a+b (x86)[1]").unwrap();
+        assert!(pattern.is_match("a+b (x86)[1]"));
+        assert!(!pattern.is_match("aab x861"));
+    }
+
+    #[test]
+    fn invalid_target() {
+        let target = |s: &str| toml::from_str::<Target>(s).unwrap();
+        assert!(MatcherSet::new(&[target("user = \"alice\"")]).is_err());
+        assert!(MatcherSet::new(&[target("name_glob = \"a*\"\nname_regex = \"a.*\"")]).is_err());
+        assert!(MatcherSet::new(&[target("name_regex = \"(\"")]).is_err());
+        assert!(MatcherSet::new(&[target("name_glob = \"a*\"\nuser = \"alice\"")]).is_ok());
+    }
+}
@@ -1,31 +1,352 @@
-use sysinfo::System;
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    time::{Duration, Instant},
+};
 
+use anyhow::anyhow;
+use sysinfo::{Pid, ProcessStatus, Signal, System, Users};
+
+use crate::config::{KillPolicy, Target};
+
+#[cfg(target_os = "linux")]
+mod linux;
+mod lockout;
+mod matcher;
+mod monitor;
+mod report;
+#[cfg(unix)]
+mod unix;
 #[cfg(windows)]
 mod windows;
 
+pub use lockout::Lockout;
+pub use matcher::MatcherSet;
+pub use monitor::{ProcessEvent, ProcessMonitor};
+pub use report::{Outcome, Report};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// OS error meaning the process no longer exists.
+#[cfg(unix)]
+const GONE: i32 = libc::ESRCH;
 #[cfg(windows)]
-pub unsafe fn kill_process_by_name(process: &str) -> anyhow::Result<()> {
-    let s = System::new_all();
+const GONE: i32 = windows::NOT_FOUND;
+
+fn parse_signal(name: &str) -> anyhow::Result<Signal> {
+    let name = name.trim().to_ascii_uppercase();
+    Ok(match name.strip_prefix("SIG").unwrap_or(&name) {
+        "TERM" => Signal::Term,
+        "INT" => Signal::Interrupt,
+        "HUP" => Signal::Hangup,
+        "QUIT" => Signal::Quit,
+        "USR1" => Signal::User1,
+        "USR2" => Signal::User2,
+        "KILL" => Signal::Kill,
+        _ => return Err(anyhow!("Unsupported signal: {name:?}")),
+    })
+}
+
+/// Kill processes matching targets, send graceful signal first if grace period is set.
+#[derive(Debug)]
+pub struct Killer {
+    targets: MatcherSet,
+    signal: Signal,
+    grace_period: Duration,
+    tree: bool,
+    launcher: Option<String>,
+    verify_timeout: Duration,
+    retries: u32,
+}
+
+impl Killer {
+    pub fn new(targets: &[Target], policy: &KillPolicy) -> anyhow::Result<Self> {
+        Ok(Self {
+            targets: MatcherSet::new(targets)?,
+            signal: parse_signal(policy.signal())?,
+            grace_period: policy.grace_period(),
+            tree: policy.tree(),
+            launcher: policy.launcher().map(ToString::to_string),
+            verify_timeout: policy.verify_timeout(),
+            retries: policy.retries(),
+        })
+    }
+
+    pub fn has_targets(&self) -> bool {
+        !self.targets.is_empty()
+    }
+
+    /// Any target running right now.
+    pub fn is_running(&self) -> bool {
+        !self.find_processes(&System::new_all()).is_empty()
+    }
 
-    let pids = s
-        .processes_by_exact_name(process)
-        .map(|p| p.pid().as_u32())
-        .collect::<Vec<_>>();
+    /// Matched processes, with their launcher first and descendants last if configured.
+    fn find_processes(&self, system: &System) -> Vec<Pid> {
+        let users = Users::new_with_refreshed_list();
+        let matched = system
+            .processes()
+            .values()
+            // Threads are listed as processes on Linux, signal goes to the whole process anyway
+            .filter(|p| p.thread_kind().is_none())
+            .filter(|p| self.targets.matches(p, &users))
+            .map(|p| p.pid())
+            .collect::<Vec<_>>();
 
-    windows::kill(pids)?;
+        let launchers = self.launcher.as_ref().map_or_else(Vec::new, |launcher| {
+            matched
+                .iter()
+                .filter_map(|pid| system.process(*pid)?.parent())
+                .filter(|parent| {
+                    system
+                        .process(*parent)
+                        .is_some_and(|p| p.name() == launcher)
+                })
+                .collect()
+        });
+
+        let mut roots = launchers;
+        roots.extend(matched);
+        // Never walk into ourselves, e.g. client started by the launcher
+        let mut seen = sysinfo::get_current_pid()
+            .into_iter()
+            .collect::<HashSet<_>>();
+        roots.retain(|pid| seen.insert(*pid));
+        if !self.tree {
+            return roots;
+        }
+
+        let mut children: HashMap<Pid, Vec<Pid>> = HashMap::new();
+        for process in system.processes().values() {
+            if process.thread_kind().is_some() {
+                continue;
+            }
+            if let Some(parent) = process.parent() {
+                children.entry(parent).or_default().push(process.pid());
+            }
+        }
+        // Breadth first, so parents are signalled before their children
+        let mut ret = roots;
+        let mut index = 0;
+        while index < ret.len() {
+            for child in children.get(&ret[index]).into_iter().flatten() {
+                if seen.insert(*child) {
+                    ret.push(*child);
+                }
+            }
+            index += 1;
+        }
+        ret
+    }
+
+    /// Block until every matched process is gone or out of retries.
+    pub unsafe fn kill(&self) -> Report {
+        let mut system = System::new_all();
+        let mut report = Report::default();
+        let mut remaining = vec![];
+        for pid in self.find_processes(&system) {
+            match Handle::open(&system, pid) {
+                Some(handle) => remaining.push(handle),
+                None => {
+                    let name = system.process(pid).map_or("", |p| p.name());
+                    report.push(pid.as_u32(), name, Outcome::AlreadyGone);
+                }
+            }
+        }
+
+        if !self.grace_period.is_zero() && Self::graceful_supported() {
+            remaining.retain_mut(|handle| {
+                let ret = handle.signal(&mut system, self.signal);
+                handle.settle(ret, &mut report, || format!("{:?} sent", self.signal))
+            });
+            Self::wait_exit(&mut remaining, &mut system, self.grace_period, &mut report);
+        }
+
+        for attempt in 0..=self.retries {
+            if remaining.is_empty() {
+                break;
+            }
+            if attempt > 0 {
+                log::warn!(
+                    "{} process(es) still running, retry {attempt}/{}",
+                    remaining.len(),
+                    self.retries
+                );
+            }
+            remaining.retain_mut(|handle| {
+                let ret = handle.force_kill(&mut system);
+                handle.settle(ret, &mut report, || "force kill sent".to_string())
+            });
+            Self::wait_exit(
+                &mut remaining,
+                &mut system,
+                self.verify_timeout,
+                &mut report,
+            );
+        }
+
+        for handle in remaining {
+            report.push(handle.pid.as_u32(), &handle.name, Outcome::StillRunning);
+        }
+        log::info!("Kill finished: {report}");
+        report
+    }
 
-    Ok(())
+    /// Poll until every handle exited or `timeout` passed, exited ones are moved to report.
+    fn wait_exit(
+        remaining: &mut Vec<Handle>,
+        system: &mut System,
+        timeout: Duration,
+        report: &mut Report,
+    ) {
+        let deadline = Instant::now() + timeout;
+        loop {
+            remaining.retain(|handle| {
+                let alive = handle.is_alive(system);
+                if !alive {
+                    report.push(handle.pid.as_u32(), &handle.name, handle.exited_outcome());
+                }
+                alive
+            });
+            if remaining.is_empty() || Instant::now() >= deadline {
+                break;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    #[cfg(unix)]
+    fn graceful_supported() -> bool {
+        true
+    }
+
+    #[cfg(windows)]
+    fn graceful_supported() -> bool {
+        false
+    }
 }
 
-#[cfg(unix)]
-pub unsafe fn kill_process_by_name(process: &str) -> anyhow::Result<()> {
-    let s = System::new_all();
+/// Matched process pinned at lookup time, so a recycled pid is never signalled.
+///
+/// On Linux a pidfd is held, elsewhere the pid is re-checked against its start time.
+struct Handle {
+    pid: Pid,
+    name: String,
+    start_time: u64,
+    /// Any signal delivered, so disappearing afterwards counts as killed
+    signalled: bool,
+    #[cfg(target_os = "linux")]
+    pidfd: Option<linux::Pidfd>,
+}
+
+impl Handle {
+    fn open(system: &System, pid: Pid) -> Option<Self> {
+        let process = system.process(pid)?;
+        let handle = Self {
+            pid,
+            name: process.name().to_string(),
+            start_time: process.start_time(),
+            signalled: false,
+            #[cfg(target_os = "linux")]
+            pidfd: match linux::Pidfd::open(pid.as_u32()) {
+                Ok(pidfd) => Some(pidfd),
+                Err(e) if e.raw_os_error() == Some(GONE) => return None,
+                Err(e) => {
+                    log::debug!("Pid {pid}: pidfd unavailable, fallback to pid: {e}");
+                    None
+                }
+            },
+        };
+        // Process may be replaced between snapshot and pidfd_open
+        let mut fresh = System::new();
+        if !handle.same_process(&mut fresh) {
+            log::warn!("Pid {pid}: replaced by another process, skip");
+            return None;
+        }
+        Some(handle)
+    }
+
+    fn same_process(&self, system: &mut System) -> bool {
+        system.refresh_process(self.pid)
+            && system
+                .process(self.pid)
+                .is_some_and(|p| p.start_time() == self.start_time)
+    }
+
+    fn exited_outcome(&self) -> Outcome {
+        if self.signalled {
+            Outcome::Killed
+        } else {
+            Outcome::AlreadyGone
+        }
+    }
+
+    /// Record result of a signal, returns whether the process should be waited for.
+    fn settle(
+        &mut self,
+        ret: io::Result<()>,
+        report: &mut Report,
+        sent: impl FnOnce() -> String,
+    ) -> bool {
+        let outcome = match ret {
+            Ok(()) => {
+                log::debug!("Pid {}: {}", self.pid, sent());
+                self.signalled = true;
+                return true;
+            }
+            Err(e) if e.raw_os_error() == Some(GONE) => self.exited_outcome(),
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Outcome::PermissionDenied,
+            Err(e) => {
+                log::error!("Pid {}: {e}", self.pid);
+                return true;
+            }
+        };
+        report.push(self.pid.as_u32(), &self.name, outcome);
+        false
+    }
+
+    fn gone() -> io::Error {
+        io::Error::from_raw_os_error(GONE)
+    }
 
-    for pid in s.processes_by_exact_name(process) {
-        if !pid.kill() {
-            log::error!("Fail to kill {}", pid.pid());
+    #[cfg(unix)]
+    fn signal(&self, system: &mut System, signal: Signal) -> io::Result<()> {
+        #[cfg(target_os = "linux")]
+        if let Some(ref pidfd) = self.pidfd {
+            return pidfd.send_signal(signal);
+        }
+        if !self.same_process(system) {
+            return Err(Self::gone());
         }
+        unix::kill(self.pid, signal)
+    }
+
+    #[cfg(windows)]
+    fn signal(&self, _system: &mut System, _signal: Signal) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
     }
 
-    Ok(())
+    fn is_alive(&self, system: &mut System) -> bool {
+        #[cfg(target_os = "linux")]
+        if let Some(ref pidfd) = self.pidfd {
+            return !pidfd.exited();
+        }
+        self.same_process(system)
+            && system
+                .process(self.pid)
+                .is_some_and(|p| p.status() != ProcessStatus::Zombie)
+    }
+
+    #[cfg(unix)]
+    unsafe fn force_kill(&self, system: &mut System) -> io::Result<()> {
+        self.signal(system, Signal::Kill)
+    }
+
+    #[cfg(windows)]
+    unsafe fn force_kill(&self, system: &mut System) -> io::Result<()> {
+        if !self.same_process(system) {
+            return Err(Self::gone());
+        }
+        windows::kill(self.pid.as_u32())
+    }
 }
@@ -0,0 +1,303 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::JoinHandle,
+    time::Duration,
+};
+
+use log::info;
+use sysinfo::{Pid, ProcessRefreshKind, System, UpdateKind, Users};
+use tokio::sync::broadcast;
+
+use super::MatcherSet;
+
+const SCAN_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Target process seen starting or exiting, `running` is number of targets afterwards.
+#[derive(Clone, Debug)]
+pub enum ProcessEvent {
+    Started {
+        pid: u32,
+        name: String,
+        running: usize,
+    },
+    Exited {
+        pid: u32,
+        name: String,
+        running: usize,
+    },
+}
+
+impl Display for ProcessEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Started { pid, name, .. } => write!(f, "Target {name} ({pid}) started"),
+            Self::Exited { pid, name, .. } => write!(f, "Target {name} ({pid}) exited"),
+        }
+    }
+}
+
+/// Watch targets in background thread, through proc connector on Linux if permitted,
+/// otherwise by rescanning process list.
+///
+/// Targets already running when started are tracked without `Started` event.
+pub struct ProcessMonitor {
+    handle: JoinHandle<()>,
+    stop: Arc<AtomicBool>,
+    events: broadcast::Sender<ProcessEvent>,
+}
+
+impl ProcessMonitor {
+    pub fn start(targets: MatcherSet) -> Self {
+        let (events, _) = broadcast::channel(64);
+        let stop = Arc::new(AtomicBool::new(false));
+        let tracker = Tracker {
+            targets,
+            users: Users::new_with_refreshed_list(),
+            running: HashMap::new(),
+            events: events.clone(),
+        };
+        let handle = {
+            let stop = stop.clone();
+            std::thread::spawn(move || tracker.run(&stop))
+        };
+        Self {
+            handle,
+            stop,
+            events,
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ProcessEvent> {
+        self.events.subscribe()
+    }
+
+    /// Block until background thread exited, takes up to a second.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.join().ok();
+    }
+}
+
+/// Events turning `before` into `after`, each with the number of targets running afterwards.
+///
+/// Starts first, so replacing one target by another never looks like none running.
+fn changes(before: &HashMap<Pid, String>, after: &HashMap<Pid, String>) -> Vec<ProcessEvent> {
+    let mut count = before.len();
+    let mut events = vec![];
+    for (pid, name) in after {
+        if !before.contains_key(pid) {
+            count += 1;
+            events.push(ProcessEvent::Started {
+                pid: pid.as_u32(),
+                name: name.clone(),
+                running: count,
+            });
+        }
+    }
+    for (pid, name) in before {
+        if !after.contains_key(pid) {
+            count -= 1;
+            events.push(ProcessEvent::Exited {
+                pid: pid.as_u32(),
+                name: name.clone(),
+                running: count,
+            });
+        }
+    }
+    events
+}
+
+struct Tracker {
+    targets: MatcherSet,
+    users: Users,
+    running: HashMap<Pid, String>,
+    events: broadcast::Sender<ProcessEvent>,
+}
+
+impl Tracker {
+    fn run(mut self, stop: &AtomicBool) {
+        let mut system = System::new();
+        self.scan(&mut system, false);
+
+        #[cfg(target_os = "linux")]
+        match super::linux::ProcConnector::open() {
+            Ok(connector) => {
+                info!("Watch targets through proc connector");
+                match self.listen(&connector, &mut system, stop) {
+                    Ok(()) => return,
+                    Err(e) => log::error!("Proc connector error, fallback to polling: {e}"),
+                }
+            }
+            Err(e) => info!("Proc connector unavailable, fallback to polling: {e}"),
+        }
+
+        while !stop.load(Ordering::Relaxed) {
+            std::thread::sleep(SCAN_INTERVAL);
+            self.scan(&mut system, true);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn listen(
+        &mut self,
+        connector: &super::linux::ProcConnector,
+        system: &mut System,
+        stop: &AtomicBool,
+    ) -> std::io::Result<()> {
+        use super::linux::ProcEvent;
+
+        while !stop.load(Ordering::Relaxed) {
+            for event in connector.recv()? {
+                match event {
+                    ProcEvent::Exec(pid) => self.check(Pid::from_u32(pid)),
+                    ProcEvent::Exit(pid) => self.exited(Pid::from_u32(pid)),
+                    ProcEvent::Overrun => {
+                        log::warn!("Proc connector dropped events, rescan");
+                        self.scan(system, true);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Refresh process list only, command line and such are read once for new processes.
+    fn scan(&mut self, system: &mut System, notify: bool) {
+        system.refresh_processes_specifics(
+            ProcessRefreshKind::new()
+                .with_exe(UpdateKind::OnlyIfNotSet)
+                .with_cmd(UpdateKind::OnlyIfNotSet)
+                .with_user(UpdateKind::OnlyIfNotSet),
+        );
+        let matched = system
+            .processes()
+            .values()
+            // Threads are listed as processes on Linux
+            .filter(|p| p.thread_kind().is_none())
+            .filter(|p| self.targets.matches(p, &self.users))
+            .map(|p| (p.pid(), p.name().to_string()))
+            .collect::<HashMap<_, _>>();
+        let running = std::mem::replace(&mut self.running, matched);
+        if !notify {
+            return;
+        }
+        for event in changes(&running, &self.running) {
+            self.emit(event);
+        }
+    }
+
+    /// Match single process after exec, only this process is read.
+    fn check(&mut self, pid: Pid) {
+        let mut system = System::new();
+        let matched = system.refresh_process_specifics(
+            pid,
+            ProcessRefreshKind::new()
+                .with_exe(UpdateKind::Always)
+                .with_cmd(UpdateKind::Always)
+                .with_user(UpdateKind::Always),
+        ) && system
+            .process(pid)
+            .is_some_and(|p| p.thread_kind().is_none() && self.targets.matches(p, &self.users));
+
+        if !matched {
+            // Replaced itself with something else
+            self.exited(pid);
+            return;
+        }
+        // Name may change on exec even if still matched
+        let name = system.process(pid).unwrap().name().to_string();
+        if self.running.insert(pid, name.clone()).is_none() {
+            self.emit(ProcessEvent::Started {
+                pid: pid.as_u32(),
+                name,
+                running: self.running.len(),
+            });
+        }
+    }
+
+    fn exited(&mut self, pid: Pid) {
+        if let Some(name) = self.running.remove(&pid) {
+            self.emit(ProcessEvent::Exited {
+                pid: pid.as_u32(),
+                name,
+                running: self.running.len(),
+            });
+        }
+    }
+
+    fn emit(&self, event: ProcessEvent) {
+        info!("{event}");
+        self.events.send(event).ok();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(pids: &[u32]) -> HashMap<Pid, String> {
+        pids.iter()
+            .map(|pid| (Pid::from_u32(*pid), format!("game{pid}")))
+            .collect()
+    }
+
+    /// `(started, pid, running)` of each event.
+    fn summary(events: Vec<ProcessEvent>) -> Vec<(bool, u32, usize)> {
+        events
+            .into_iter()
+            .map(|event| match event {
+                ProcessEvent::Started { pid, running, .. } => (true, pid, running),
+                ProcessEvent::Exited { pid, running, .. } => (false, pid, running),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn changes_count_running() {
+        assert_eq!(
+            summary(changes(&targets(&[]), &targets(&[1]))),
+            [(true, 1, 1)]
+        );
+        assert_eq!(
+            summary(changes(&targets(&[1]), &targets(&[]))),
+            [(false, 1, 0)]
+        );
+        assert_eq!(summary(changes(&targets(&[1, 2]), &targets(&[1, 2]))), []);
+
+        // Order among new targets follows the map, counts still go up one by one
+        let events = summary(changes(&targets(&[1]), &targets(&[1, 2, 3])));
+        assert!(events.iter().all(|(started, _, _)| *started));
+        assert_eq!(events.iter().map(|e| e.2).collect::<Vec<_>>(), [2, 3]);
+    }
+
+    #[test]
+    fn replaced_target_never_reaches_zero() {
+        let events = summary(changes(&targets(&[1]), &targets(&[2])));
+        assert_eq!(events, [(true, 2, 2), (false, 1, 1)]);
+    }
+
+    #[test]
+    fn exited_counts_remaining() {
+        let (events, mut receiver) = broadcast::channel(8);
+        let mut tracker = Tracker {
+            targets: MatcherSet::new(&[]).unwrap(),
+            users: Users::new(),
+            running: targets(&[1, 2]),
+            events,
+        };
+        tracker.exited(Pid::from_u32(1));
+        // Untracked pid is ignored
+        tracker.exited(Pid::from_u32(1));
+        tracker.exited(Pid::from_u32(2));
+        let mut seen = vec![];
+        while let Ok(event) = receiver.try_recv() {
+            seen.push(event);
+        }
+        assert_eq!(summary(seen), [(false, 1, 1), (false, 2, 0)]);
+    }
+}
@@ -0,0 +1,81 @@
+use std::fmt::Display;
+
+/// Final state of a matched process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Exited after our signal, gracefully or forcefully
+    Killed,
+    /// Exited before any signal reached it
+    AlreadyGone,
+    PermissionDenied,
+    /// Survived every retry
+    StillRunning,
+}
+
+impl Outcome {
+    const ALL: [Self; 4] = [
+        Self::Killed,
+        Self::AlreadyGone,
+        Self::PermissionDenied,
+        Self::StillRunning,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Killed => "killed",
+            Self::AlreadyGone => "already gone",
+            Self::PermissionDenied => "permission denied",
+            Self::StillRunning => "still running",
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Entry {
+    pub pid: u32,
+    pub name: String,
+    pub outcome: Outcome,
+}
+
+/// Result of one kill request, one entry per matched process.
+#[derive(Clone, Debug, Default)]
+pub struct Report(Vec<Entry>);
+
+impl Report {
+    pub(super) fn push(&mut self, pid: u32, name: &str, outcome: Outcome) {
+        log::info!("Pid {pid} ({name}): {}", outcome.as_str());
+        self.0.push(Entry {
+            pid,
+            name: name.to_string(),
+            outcome,
+        });
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.0
+    }
+
+    pub fn count(&self, outcome: Outcome) -> usize {
+        self.0.iter().filter(|e| e.outcome == outcome).count()
+    }
+
+    /// No matched process is left running.
+    pub fn is_complete(&self) -> bool {
+        self.count(Outcome::PermissionDenied) == 0 && self.count(Outcome::StillRunning) == 0
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "no process matched");
+        }
+        let parts = Outcome::ALL
+            .iter()
+            .map(|outcome| (self.count(*outcome), outcome.as_str()))
+            .filter(|(count, _)| *count > 0)
+            .map(|(count, name)| format!("{count} {name}"))
+            .collect::<Vec<_>>();
+        write!(f, "{}", parts.join(", "))
+    }
+}
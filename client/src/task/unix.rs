@@ -0,0 +1,25 @@
+use std::io;
+
+use sysinfo::{Pid, Signal};
+
+pub(crate) fn signal_number(signal: Signal) -> Option<libc::c_int> {
+    Some(match signal {
+        Signal::Term => libc::SIGTERM,
+        Signal::Interrupt => libc::SIGINT,
+        Signal::Hangup => libc::SIGHUP,
+        Signal::Quit => libc::SIGQUIT,
+        Signal::User1 => libc::SIGUSR1,
+        Signal::User2 => libc::SIGUSR2,
+        Signal::Kill => libc::SIGKILL,
+        _ => return None,
+    })
+}
+
+/// Plain kill(2), caller must make sure `pid` still refers to the target.
+pub(crate) fn kill(pid: Pid, signal: Signal) -> io::Result<()> {
+    let signal = signal_number(signal).ok_or_else(|| io::Error::from_raw_os_error(libc::EINVAL))?;
+    if unsafe { libc::kill(pid.as_u32() as libc::pid_t, signal) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -1,93 +1,642 @@
-use std::time::Duration;
+use std::{
+    collections::VecDeque,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
+use anyhow::anyhow;
 use futures_util::{SinkExt as _, StreamExt};
-use log::warn;
+use log::{info, warn};
 use reqwest_websocket::{CloseCode, Message, RequestBuilderExt, WebSocket};
-use tokio::{sync::mpsc, time::Instant};
+use serde::Serialize;
+use tap::TapFallible;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
 
-use crate::{task::kill_process_by_name, TERMINATE_TARGET};
+use crate::{
+    backoff::Backoff,
+    config::Config,
+    hook,
+    task::{Killer, Lockout, Outcome, ProcessEvent},
+};
 
-#[derive(Clone, Copy, Debug)]
+/// Messages sent to server, must match `WebData` on server side.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type")]
+pub enum WebData<'a> {
+    Auth {
+        uuid: &'a str,
+    },
+    Redeem {
+        code: &'a str,
+    },
+    RequestTerminate {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        triggered_at: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<&'a str>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        lockout: Option<u64>,
+    },
+    Poke {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<&'a str>,
+    },
+    Cancel,
+    AllClear {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        target: Option<&'a str>,
+    },
+    TargetStarted,
+    TargetExited,
+}
+
+impl WebData<'_> {
+    fn to_message(&self) -> Message {
+        Message::Text(serde_json::to_string(self).unwrap())
+    }
+}
+
+/// `friend` is user name or uuid on server, `None` means everyone.
+#[derive(Clone, Debug)]
 pub enum WebEvent {
-    SendTerminate,
+    Terminate {
+        friend: Option<String>,
+        /// Seconds, receiver's default if `None`
+        lockout: Option<u64>,
+    },
+    Poke {
+        friend: Option<String>,
+    },
+    /// Drop queued triggers and lift the lockout set by own last terminate, here and on friends
+    Cancel,
+    ToggleDoNotDisturb,
+    AllClear {
+        friend: Option<String>,
+    },
+    Stop,
+}
+
+/// Why `handle_websocket` returned.
+#[derive(Debug)]
+enum Disconnect {
+    /// User requested exit
     Stop,
+    Lost,
+    /// Server is going away and asked to reconnect after this delay
+    Shutdown(Duration),
+}
+
+const MAX_PENDING_TRIGGERS: usize = 32;
+
+#[derive(Debug)]
+struct PendingTrigger {
+    at: SystemTime,
+    friend: Option<String>,
+    lockout: Option<u64>,
+}
+
+/// Triggers pressed while offline, forwarded once reconnected if still fresh.
+#[derive(Debug, Default)]
+struct PendingTriggers(VecDeque<PendingTrigger>);
+
+impl PendingTriggers {
+    fn push(&mut self, friend: Option<String>, lockout: Option<u64>) {
+        if self.0.len() >= MAX_PENDING_TRIGGERS {
+            self.0.pop_front();
+        }
+        self.0.push_back(PendingTrigger {
+            at: SystemTime::now(),
+            friend,
+            lockout,
+        });
+    }
+
+    fn clear(&mut self) {
+        if !self.0.is_empty() {
+            info!("Drop {} queued trigger(s)", self.0.len());
+            self.0.clear();
+        }
+    }
+
+    /// Drop triggers older than `freshness`.
+    fn drop_stale(&mut self, freshness: Duration) {
+        let now = SystemTime::now();
+        let total = self.0.len();
+        self.0
+            .retain(|trigger| now.duration_since(trigger.at).unwrap_or_default() <= freshness);
+        if self.0.len() < total {
+            info!("Drop {} stale queued trigger(s)", total - self.0.len());
+        }
+    }
+
+    /// Oldest trigger as request to forward, removed by `pop` once sent.
+    fn peek(&self) -> Option<WebData<'_>> {
+        self.0.front().map(|trigger| WebData::RequestTerminate {
+            triggered_at: Some(
+                trigger
+                    .at
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs(),
+            ),
+            target: trigger.friend.as_deref(),
+            lockout: trigger.lockout,
+        })
+    }
+
+    fn pop(&mut self) {
+        self.0.pop_front();
+    }
+}
+
+// Private close codes, must match server side
+/// Credential will never be accepted
+const CREDENTIAL_REJECTED: u16 = 4001;
+/// Replaced by a newer session of the same user
+const SIGNED_IN_ELSEWHERE: u16 = 4002;
+
+/// TCP connect to an unreachable host may otherwise hang for minutes.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connection that stayed open this long was accepted by server, a refused one is closed at once.
+const STABLE_CONNECTION: Duration = Duration::from_secs(60);
+
+/// Server refused us for good (bad credential or signed in elsewhere), reconnecting would not help.
+#[derive(Debug)]
+struct Rejected(String);
+
+impl std::fmt::Display for Rejected {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rejected by server: {}", self.0)
+    }
 }
 
+impl std::error::Error for Rejected {}
+
+/// State kept across reconnects.
+struct Connection {
+    config: Config,
+    config_path: String,
+    invite: Option<String>,
+    killer: Arc<Killer>,
+    lockout: Lockout,
+    /// Uuid whose terminate set the running lockout, own uuid for local triggers
+    lockout_owner: Option<String>,
+    /// Target start and exit, shared with friends
+    presence: broadcast::Receiver<ProcessEvent>,
+    /// Whether friends were last told a target is running
+    shared_running: bool,
+    receiver: mpsc::Receiver<WebEvent>,
+    pending: PendingTriggers,
+    do_not_disturb: bool,
+}
+
+/// Keep connected to server until stopped, reconnect with backoff when connection is lost.
 pub async fn make_connection(
     remote: String,
-    uuid: String,
+    config: Config,
+    config_path: String,
+    invite: Option<String>,
+    killer: Arc<Killer>,
+    events: broadcast::Receiver<ProcessEvent>,
     receiver: mpsc::Receiver<WebEvent>,
 ) -> anyhow::Result<()> {
+    let mut connection = Connection {
+        config,
+        config_path,
+        invite,
+        presence: events.resubscribe(),
+        shared_running: false,
+        lockout: Lockout::start(killer.clone(), events),
+        lockout_owner: None,
+        killer,
+        receiver,
+        pending: PendingTriggers::default(),
+        do_not_disturb: false,
+    };
+    let mut backoff = Backoff::default();
+    loop {
+        info!("Connecting to {remote}");
+        let Some(ret) = connection.connect(&remote).await else {
+            break;
+        };
+        let ret = match ret {
+            Ok(websocket) => {
+                info!("Connected to {remote}");
+                let connected_at = Instant::now();
+                let ret = connection.handle_websocket(websocket).await;
+                if connected_at.elapsed() >= STABLE_CONNECTION {
+                    backoff.reset();
+                }
+                ret
+            }
+            Err(e) => Err(e),
+        };
+
+        let delay = match ret {
+            Ok(Disconnect::Stop) => break,
+            Ok(Disconnect::Shutdown(after)) if !after.is_zero() => Backoff::jitter(after),
+            // Jitter of zero is zero, would reconnect in a tight loop
+            Ok(Disconnect::Lost | Disconnect::Shutdown(_)) => backoff.next_delay(),
+            Err(e) => {
+                if e.is::<Rejected>() {
+                    return Err(e);
+                }
+                warn!("Connection error: {e:?}");
+                backoff.next_delay()
+            }
+        };
+        info!("Disconnected, reconnect in {delay:?}");
+
+        if connection.wait_offline(delay).await {
+            break;
+        }
+    }
+    Ok(())
+}
+
+async fn connect(remote: &str) -> anyhow::Result<WebSocket> {
     let response = reqwest::Client::default()
-        .get(&remote)
+        .get(remote)
         .upgrade()
         .send()
         .await?;
 
-    let websocket = response.into_websocket().await?;
-
-    handle_websocket(websocket, &uuid, receiver).await?;
-    Ok(())
+    Ok(response.into_websocket().await?)
 }
 
-pub async fn handle_websocket(
-    mut socket: WebSocket,
-    uuid: &str,
-    mut outer_receiver: mpsc::Receiver<WebEvent>,
-) -> anyhow::Result<()> {
-    let mut interval = tokio::time::interval(Duration::from_secs(10));
-    let mut last_seen = Instant::now();
-    socket.send(Message::Text(uuid.to_string())).await?;
-    let (mut sender, mut receiver) = socket.split();
-    loop {
-        tokio::select! {
-            Some(Ok(msg)) = receiver.next() => {
-                match msg {
-                    Message::Text(s) => {
-                        if s == "auth" {
-                            sender.send(Message::Text(uuid.to_string())).await?;
-                        }
-                    },
-                    Message::Pong(_) => {
-                        last_seen = Instant::now();
-                    },
-                    Message::Close { code, reason } => {
-                        warn!("Server closed: {code} {reason}");
-                        break
-                    },
-                    _ => {}
+impl Connection {
+    /// Kill targets, then keep them away for `lockout` seconds or configured default.
+    ///
+    /// `from` is uuid of the requesting friend, `None` for own triggers.
+    fn terminate_locally(&mut self, from: Option<&str>, lockout: Option<u64>) {
+        let lockout = lockout.unwrap_or(self.config.kill().lockout());
+        if lockout > 0 && self.lockout.extend(Duration::from_secs(lockout)) {
+            self.lockout_owner = Some(from.unwrap_or(self.config.uuid()).to_string());
+        }
+        let killer = self.killer.clone();
+        let hooks = self.config.hooks().clone();
+        std::thread::spawn(move || {
+            // Hooks are for tidying up around a kill, skip them when there is nothing to kill
+            if !killer.is_running() {
+                info!("No target running, nothing to kill");
+                return;
+            }
+            let report = hook::around_kill(&hooks, || unsafe { killer.kill() });
+            if !report.is_complete() {
+                let survivors = report
+                    .entries()
+                    .iter()
+                    .filter(|e| !matches!(e.outcome, Outcome::Killed | Outcome::AlreadyGone))
+                    .map(|e| format!("{} ({}, {})", e.name, e.pid, e.outcome.as_str()))
+                    .collect::<Vec<_>>();
+                log::error!("Unable to kill: {}", survivors.join(", "));
+            }
+        });
+    }
+
+    /// Handle local events until `delay` passed, returns `true` if stop requested.
+    async fn wait_offline(&mut self, delay: Duration) -> bool {
+        let deadline = Instant::now() + delay;
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep_until(deadline) => return false,
+                event = self.receiver.recv() => if self.handle_offline(event) {
+                    return true;
+                },
+            }
+        }
+    }
+
+    /// Connect while still handling local events, `None` if stop requested meanwhile.
+    async fn connect(&mut self, remote: &str) -> Option<anyhow::Result<WebSocket>> {
+        let connecting = tokio::time::timeout(CONNECT_TIMEOUT, connect(remote));
+        tokio::pin!(connecting);
+        loop {
+            tokio::select! {
+                ret = &mut connecting => {
+                    return Some(ret.unwrap_or_else(|_| {
+                        Err(anyhow!("Connect timed out after {CONNECT_TIMEOUT:?}"))
+                    }));
                 }
+                event = self.receiver.recv() => if self.handle_offline(event) {
+                    return None;
+                },
             }
+        }
+    }
 
-            _ = interval.tick() => {
-                if  (Instant::now() - last_seen).as_secs() > 30 {
-                    log::error!("Server not response in ping check");
-                    break;
+    /// Lift lockout if the last terminate extending it came from `from`, `None` for own.
+    fn cancel_lockout(&mut self, from: Option<&str>) {
+        if self.lockout_owner.as_deref() == Some(from.unwrap_or(self.config.uuid())) {
+            self.lockout_owner = None;
+            self.lockout.lift();
+        }
+    }
+
+    /// Returns `true` if stop requested.
+    fn handle_offline(&mut self, event: Option<WebEvent>) -> bool {
+        match event {
+            Some(WebEvent::Terminate { friend, lockout }) => {
+                warn!("Not connected, queue terminate request");
+                if friend.is_none() {
+                    self.terminate_locally(None, lockout);
                 }
+                self.pending.push(friend, lockout);
+            }
+            Some(WebEvent::Poke { .. }) => warn!("Not connected, poke dropped"),
+            Some(WebEvent::AllClear { friend }) => {
+                if friend.is_none() {
+                    self.lockout.lift();
+                }
+                warn!("Not connected, all clear not sent to friends");
+            }
+            Some(WebEvent::Cancel) => {
+                self.pending.clear();
+                self.cancel_lockout(None);
+                warn!("Not connected, cancel not sent to friends");
+            }
+            Some(WebEvent::ToggleDoNotDisturb) => self.toggle_do_not_disturb(),
+            Some(WebEvent::Stop) | None => return true,
+        }
+        false
+    }
+
+    /// Message for friends if `running` differs from what they were last told.
+    fn share_presence(&mut self, running: bool) -> Option<WebData<'static>> {
+        if running == self.shared_running {
+            return None;
+        }
+        self.shared_running = running;
+        Some(if running {
+            WebData::TargetStarted
+        } else {
+            WebData::TargetExited
+        })
+    }
+
+    fn toggle_do_not_disturb(&mut self) {
+        self.do_not_disturb = !self.do_not_disturb;
+        info!(
+            "Do not disturb {}",
+            if self.do_not_disturb { "on" } else { "off" }
+        );
+    }
 
-                sender.send(Message::Ping(vec![])).await?;
+    async fn handle_websocket(&mut self, mut socket: WebSocket) -> anyhow::Result<Disconnect> {
+        let mut interval = tokio::time::interval(Duration::from_secs(10));
+        let mut last_seen = Instant::now();
+        let mut reason = Disconnect::Lost;
+        let mut presence_open = true;
+        // Events buffered while offline are stale
+        self.presence = self.presence.resubscribe();
+        match self.invite {
+            Some(ref code) => socket.send(WebData::Redeem { code }.to_message()).await?,
+            None => {
+                socket
+                    .send(
+                        WebData::Auth {
+                            uuid: self.config.uuid(),
+                        }
+                        .to_message(),
+                    )
+                    .await?
             }
+        }
+        // Removed only after sent, unsent ones wait for next connection
+        self.pending.drop_stale(self.config.queue_freshness());
+        while let Some(message) = self.pending.peek().map(|data| data.to_message()) {
+            info!("Forward queued terminate request");
+            socket.send(message).await?;
+            self.pending.pop();
+        }
+        // Edges missed while offline were dropped above, share current state instead
+        let killer = self.killer.clone();
+        let running = tokio::task::spawn_blocking(move || killer.is_running())
+            .await
+            .unwrap_or(self.shared_running);
+        if let Some(data) = self.share_presence(running) {
+            socket.send(data.to_message()).await?;
+        }
+        let (mut sender, mut receiver) = socket.split();
+        loop {
+            tokio::select! {
+                msg = receiver.next() => {
+                    let Some(Ok(msg)) = msg else {
+                        warn!("Connection lost");
+                        break;
+                    };
+                    last_seen = Instant::now();
+                    match msg {
+                        Message::Text(s) => {
+                            if s == "auth" {
+                                sender.send(WebData::Auth { uuid: self.config.uuid() }.to_message()).await?;
+                            } else if let Some(args) = s.strip_prefix("terminate ") {
+                                let (uuid, lockout) = match args.split_once(' ') {
+                                    Some((uuid, lockout)) => (uuid, lockout.parse().ok()),
+                                    None => (args, None),
+                                };
+                                if self.do_not_disturb {
+                                    info!("Do not disturb, ignore terminate request from {uuid}");
+                                    continue;
+                                }
+                                info!("Receive terminate request from {uuid}");
+                                self.terminate_locally(Some(uuid), lockout);
+                            } else if let Some(uuid) = s.strip_prefix("poke ") {
+                                if !self.do_not_disturb {
+                                    info!("Poked by {uuid}");
+                                }
+                            } else if let Some(uuid) = s.strip_prefix("cancel ") {
+                                info!("{uuid} cancelled their terminate request");
+                                self.cancel_lockout(Some(uuid));
+                            } else if let Some((started, args)) = s
+                                .strip_prefix("started ")
+                                .map(|args| (true, args))
+                                .or_else(|| s.strip_prefix("exited ").map(|args| (false, args)))
+                            {
+                                if self.do_not_disturb {
+                                    continue;
+                                }
+                                // Display name follows uuid if friend has one
+                                let friend = args.split_once(' ').map_or(args, |(_, name)| name);
+                                if started {
+                                    info!("{friend} just launched the game");
+                                } else {
+                                    info!("{friend} left the game");
+                                }
+                            } else if let Some(uuid) = s.strip_prefix("all-clear ") {
+                                info!("All clear from {uuid}");
+                                self.lockout.lift();
+                            } else if let Some(after) = s.strip_prefix("shutdown ") {
+                                let after = after.parse().unwrap_or(0);
+                                info!("Server shutting down, reconnect after {after}s");
+                                reason = Disconnect::Shutdown(Duration::from_secs(after));
+                            } else if let Some(uuid) = s.strip_prefix("credential ") {
+                                info!("Enrolled with invite code, save credential");
+                                self.invite.take();
+                                self.config.set_uuid(uuid.to_string());
+                                self.config
+                                    .write(&self.config_path)
+                                    .await
+                                    .tap_err(|e| log::error!("Write configure file error: {e:?}"))
+                                    .ok();
+                            }
+                        },
+                        Message::Close { code, reason: message } => {
+                            warn!("Server closed: {code} {message}");
+                            if [CREDENTIAL_REJECTED, SIGNED_IN_ELSEWHERE]
+                                .map(CloseCode::from)
+                                .contains(&code)
+                            {
+                                return Err(Rejected(message).into());
+                            }
+                            break
+                        },
+                        _ => {}
+                    }
+                }
 
-            Some(event) = outer_receiver.recv() => {
-                match event {
-                    WebEvent::Stop => {
-                        break
+                _ = interval.tick() => {
+                    if  (Instant::now() - last_seen).as_secs() > 30 {
+                        log::error!("Server not response in ping check");
+                        break;
                     }
-                    WebEvent::SendTerminate => {
-                        std::thread::spawn(|| unsafe { kill_process_by_name(TERMINATE_TARGET) });
-                        sender.send(Message::Text("Terminate".to_string())).await?;
+
+                    sender.send(Message::Ping(vec![])).await?;
+                }
+
+                event = self.presence.recv(), if presence_open => {
+                    // Only share edges between none and some running, not every process
+                    let running = match event {
+                        Ok(ProcessEvent::Started { running, .. } | ProcessEvent::Exited { running, .. }) => running > 0,
+                        Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                        Err(broadcast::error::RecvError::Closed) => {
+                            presence_open = false;
+                            continue;
+                        }
+                    };
+                    if let Some(data) = self.share_presence(running) {
+                        sender.send(data.to_message()).await?;
+                    }
+                }
+
+                event = self.receiver.recv() => {
+                    let Some(event) = event else {
+                        reason = Disconnect::Stop;
+                        break;
+                    };
+                    match event {
+                        WebEvent::Stop => {
+                            reason = Disconnect::Stop;
+                            break
+                        }
+                        WebEvent::Terminate { friend, lockout } => {
+                            if friend.is_none() {
+                                self.terminate_locally(None, lockout);
+                            }
+                            let ret = sender
+                                .send(
+                                    WebData::RequestTerminate {
+                                        triggered_at: None,
+                                        target: friend.as_deref(),
+                                        lockout,
+                                    }
+                                    .to_message(),
+                                )
+                                .await;
+                            if ret.is_err() {
+                                self.pending.push(friend, lockout);
+                            }
+                            ret?;
+                        }
+                        WebEvent::Poke { friend } => {
+                            sender
+                                .send(WebData::Poke { target: friend.as_deref() }.to_message())
+                                .await?;
+                        }
+                        WebEvent::Cancel => {
+                            self.pending.clear();
+                            self.cancel_lockout(None);
+                            sender.send(WebData::Cancel.to_message()).await?;
+                        }
+                        WebEvent::ToggleDoNotDisturb => self.toggle_do_not_disturb(),
+                        WebEvent::AllClear { friend } => {
+                            if friend.is_none() {
+                                self.lockout.lift();
+                            }
+                            sender
+                                .send(WebData::AllClear { target: friend.as_deref() }.to_message())
+                                .await?;
+                        }
                     }
                 }
             }
         }
+
+        sender
+            .send(Message::Close {
+                code: CloseCode::Normal,
+                reason: "Normal exit".to_string(),
+            })
+            .await
+            .ok();
+        Ok(reason)
     }
+}
 
-    sender
-        .send(Message::Close {
-            code: CloseCode::Normal,
-            reason: "Normal exit".to_string(),
-        })
-        .await
-        .ok();
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn targets(pending: &PendingTriggers) -> Vec<Option<&str>> {
+        pending.0.iter().map(|t| t.friend.as_deref()).collect()
+    }
+
+    #[test]
+    fn pending_order_and_limit() {
+        let mut pending = PendingTriggers::default();
+        for i in 0..MAX_PENDING_TRIGGERS + 2 {
+            pending.push(Some(i.to_string()), None);
+        }
+        assert_eq!(pending.0.len(), MAX_PENDING_TRIGGERS);
+        // Oldest dropped first
+        assert_eq!(targets(&pending)[0], Some("2"));
+
+        pending.clear();
+        assert!(pending.peek().is_none());
+    }
+
+    #[test]
+    fn pending_peek_then_pop() {
+        let mut pending = PendingTriggers::default();
+        pending.push(None, Some(30));
+        pending.push(Some("bob".to_string()), None);
+
+        // Peek alone never removes, a failed send keeps the trigger
+        let first = serde_json::to_value(pending.peek().unwrap()).unwrap();
+        assert_eq!(first["type"], "RequestTerminate");
+        assert_eq!(first["lockout"], 30);
+        assert!(first["triggered_at"].as_u64().is_some());
+        assert_eq!(targets(&pending), [None, Some("bob")]);
+
+        pending.pop();
+        let second = serde_json::to_value(pending.peek().unwrap()).unwrap();
+        assert_eq!(second["target"], "bob");
+        pending.pop();
+        assert!(pending.peek().is_none());
+    }
+
+    #[test]
+    fn pending_drop_stale() {
+        let mut pending = PendingTriggers::default();
+        pending.push(Some("old".to_string()), None);
+        pending.push(Some("new".to_string()), None);
+        pending.0[0].at -= Duration::from_secs(120);
+
+        pending.drop_stale(Duration::from_secs(60));
+        assert_eq!(targets(&pending), [Some("new")]);
+    }
 }
@@ -0,0 +1,161 @@
+use std::{
+    sync::Arc,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use log::{info, warn};
+use serde::Deserialize;
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    config::Config,
+    invite::Invites,
+    monitor::ScanUpdateHelper,
+    session::Sessions,
+    types::{Command, WebBroadcastEvent, SERVER_SESSION},
+};
+
+const DEFAULT_INVITE_TTL: u64 = 86400;
+
+pub fn router() -> Router {
+    Router::new()
+        .route("/reload", post(reload))
+        .route("/invites", post(create_invite))
+        .route("/sessions", get(list_sessions))
+        .route("/all-clear", post(all_clear))
+        .route_layer(middleware::from_fn(authorize))
+}
+
+#[derive(Deserialize)]
+struct AllClear {
+    /// User name or uuid, everyone if omitted
+    target: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CreateInvite {
+    name: String,
+    /// Seconds until the code expires
+    ttl: Option<u64>,
+}
+
+/// Compare without short circuit, avoid leaking token through timing.
+fn token_eq(left: &[u8], right: &[u8]) -> bool {
+    left.len() == right.len()
+        && left
+            .iter()
+            .zip(right)
+            .fold(0u8, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+async fn authorize(
+    Extension(config): Extension<watch::Receiver<Config>>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let token = match config.borrow().admin() {
+        Some(admin) => admin.token().to_string(),
+        // Admin API disabled if no token configured
+        None => return StatusCode::NOT_FOUND.into_response(),
+    };
+
+    let authorized = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|v| token_eq(v.trim().as_bytes(), token.as_bytes()));
+
+    if !authorized {
+        warn!("Reject unauthorized admin request to {}", request.uri());
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    next.run(request).await
+}
+
+async fn reload(Extension(reload): Extension<ScanUpdateHelper>) -> impl IntoResponse {
+    info!("Receive reload request from admin API");
+    match reload.need_update().await {
+        Some(()) => (
+            StatusCode::ACCEPTED,
+            Json(serde_json::json!({"status": "reload requested"})),
+        ),
+        None => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({"status": "reload thread exited"})),
+        ),
+    }
+}
+
+async fn create_invite(
+    Extension(config): Extension<watch::Receiver<Config>>,
+    Extension(invites): Extension<Invites>,
+    Json(request): Json<CreateInvite>,
+) -> impl IntoResponse {
+    if config.borrow().web().has_name(&request.name) {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({"error": "user already exists"})),
+        );
+    }
+
+    let ttl = Duration::from_secs(request.ttl.unwrap_or(DEFAULT_INVITE_TTL));
+    let (code, expires_at) = invites.create(request.name.clone(), ttl).await;
+    info!("Create invite for {}", request.name);
+    (
+        StatusCode::CREATED,
+        Json(serde_json::json!({
+            "code": code,
+            "expires_at": expires_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs(),
+        })),
+    )
+}
+
+async fn list_sessions(Extension(sessions): Extension<Sessions>) -> impl IntoResponse {
+    Json(sessions.list())
+}
+
+/// Lift lockout of `target` or everyone, relayed to clients as `"all-clear server"`.
+async fn all_clear(
+    Extension(config): Extension<watch::Receiver<Config>>,
+    Extension(broadcast): Extension<Arc<broadcast::Sender<WebBroadcastEvent>>>,
+    Json(request): Json<AllClear>,
+) -> impl IntoResponse {
+    let target = match request.target {
+        Some(target) => match config.borrow().web().find_user(&target) {
+            Some(user) => Some(user.uuid().to_string()),
+            None => {
+                return (
+                    StatusCode::NOT_FOUND,
+                    Json(serde_json::json!({"error": "user not found"})),
+                )
+            }
+        },
+        None => None,
+    };
+    info!(
+        "Send all clear to {} from admin API",
+        target.as_deref().unwrap_or("everyone")
+    );
+    broadcast
+        .send(WebBroadcastEvent::Relay {
+            command: Command::AllClear,
+            uuid: "server".to_string(),
+            session: SERVER_SESSION,
+            target,
+        })
+        .ok();
+    (
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({"status": "all clear sent"})),
+    )
+}
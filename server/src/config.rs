@@ -1,9 +1,18 @@
+use std::{collections::HashSet, time::Duration};
+
+use anyhow::{anyhow, bail};
+use ipnet::IpNet;
 use serde::Deserialize;
 use tokio::io::AsyncReadExt;
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Config {
     web: Web,
+    admin: Option<Admin>,
+    #[serde(default)]
+    session: Session,
+    #[serde(default)]
+    shutdown: Shutdown,
 }
 
 impl Config {
@@ -12,18 +21,270 @@ impl Config {
         let mut s = String::new();
 
         f.read_to_string(&mut s).await?;
-        Ok(toml::from_str(&s)?)
+        let config: Self = toml::from_str(&s)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.web.validate()?;
+        self.session.validate()?;
+        if let Some(ref admin) = self.admin {
+            admin.validate()?;
+        }
+        Ok(())
+    }
+
+    /// Human readable list of changes from `self` to `new`.
+    pub fn diff(&self, new: &Self) -> Vec<String> {
+        let mut changes = self.web.diff(&new.web);
+        match (&self.admin, &new.admin) {
+            (None, Some(_)) => changes.push("admin: enabled".to_string()),
+            (Some(_), None) => changes.push("admin: disabled".to_string()),
+            (Some(old), Some(new)) if old.token != new.token => {
+                changes.push("admin: token changed".to_string())
+            }
+            _ => {}
+        }
+        changes.extend(self.session.diff(&new.session));
+        changes.extend(self.shutdown.diff(&new.shutdown));
+        changes
     }
 
     pub fn web(&self) -> &Web {
         &self.web
     }
+
+    pub fn admin(&self) -> Option<&Admin> {
+        self.admin.as_ref()
+    }
+
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    pub fn shutdown(&self) -> &Shutdown {
+        &self.shutdown
+    }
+}
+
+/// Behaviour on server exit, durations are in seconds.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Shutdown {
+    #[serde(default = "default_drain_timeout")]
+    drain_timeout: u64,
+    /// Hint client to reconnect after this delay, `0` means server is not coming back.
+    #[serde(default = "default_reconnect_after")]
+    reconnect_after: u64,
+}
+
+fn default_drain_timeout() -> u64 {
+    10
+}
+
+fn default_reconnect_after() -> u64 {
+    5
+}
+
+impl Shutdown {
+    /// Time to wait for clients to disconnect.
+    pub fn drain_timeout(&self) -> Duration {
+        Duration::from_secs(self.drain_timeout)
+    }
+
+    pub fn reconnect_after(&self) -> u64 {
+        self.reconnect_after
+    }
+
+    fn diff(&self, new: &Self) -> Vec<String> {
+        let mut changes = vec![];
+        if self.drain_timeout != new.drain_timeout {
+            changes.push(format!(
+                "shutdown.drain_timeout: {} -> {}",
+                self.drain_timeout, new.drain_timeout
+            ));
+        }
+        if self.reconnect_after != new.reconnect_after {
+            changes.push(format!(
+                "shutdown.reconnect_after: {} -> {}",
+                self.reconnect_after, new.reconnect_after
+            ));
+        }
+        changes
+    }
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self {
+            drain_timeout: default_drain_timeout(),
+            reconnect_after: default_reconnect_after(),
+        }
+    }
+}
+
+/// Per connection settings, durations are in seconds.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Session {
+    #[serde(default = "default_ping_interval")]
+    ping_interval: u64,
+    #[serde(default = "default_ping_timeout")]
+    ping_timeout: u64,
+    #[serde(default = "default_auth_timeout")]
+    auth_timeout: u64,
+    #[serde(default = "default_max_auth_failures")]
+    max_auth_failures: u32,
+    #[serde(default = "default_max_unauthenticated_per_ip")]
+    max_unauthenticated_per_ip: usize,
+    #[serde(default)]
+    multi_session: MultiSession,
+}
+
+/// What to do when a user authenticates while already having a session.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum MultiSession {
+    #[default]
+    Allow,
+    /// Close the old sessions
+    NewestWins,
+    /// Refuse the new session
+    RejectNew,
+}
+
+fn default_ping_interval() -> u64 {
+    15
+}
+
+fn default_ping_timeout() -> u64 {
+    45
+}
+
+fn default_auth_timeout() -> u64 {
+    60
+}
+
+fn default_max_auth_failures() -> u32 {
+    3
+}
+
+fn default_max_unauthenticated_per_ip() -> usize {
+    8
+}
+
+impl Session {
+    pub fn ping_interval(&self) -> Duration {
+        Duration::from_secs(self.ping_interval)
+    }
+
+    pub fn ping_timeout(&self) -> Duration {
+        Duration::from_secs(self.ping_timeout)
+    }
+
+    /// Time allowed for a new connection to authenticate.
+    pub fn auth_timeout(&self) -> Duration {
+        Duration::from_secs(self.auth_timeout)
+    }
+
+    pub fn max_auth_failures(&self) -> u32 {
+        self.max_auth_failures
+    }
+
+    pub fn max_unauthenticated_per_ip(&self) -> usize {
+        self.max_unauthenticated_per_ip
+    }
+
+    pub fn multi_session(&self) -> MultiSession {
+        self.multi_session
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.ping_interval == 0 {
+            bail!("session.ping_interval should be greater than 0");
+        }
+        if self.ping_timeout <= self.ping_interval {
+            bail!("session.ping_timeout should be greater than session.ping_interval");
+        }
+        if self.auth_timeout == 0 {
+            bail!("session.auth_timeout should be greater than 0");
+        }
+        if self.max_auth_failures == 0 {
+            bail!("session.max_auth_failures should be greater than 0");
+        }
+        if self.max_unauthenticated_per_ip == 0 {
+            bail!("session.max_unauthenticated_per_ip should be greater than 0");
+        }
+        Ok(())
+    }
+
+    fn diff(&self, new: &Self) -> Vec<String> {
+        let mut changes = vec![];
+        if self.ping_interval != new.ping_interval {
+            changes.push(format!(
+                "session.ping_interval: {} -> {}",
+                self.ping_interval, new.ping_interval
+            ));
+        }
+        if self.ping_timeout != new.ping_timeout {
+            changes.push(format!(
+                "session.ping_timeout: {} -> {}",
+                self.ping_timeout, new.ping_timeout
+            ));
+        }
+        if self.auth_timeout != new.auth_timeout {
+            changes.push(format!(
+                "session.auth_timeout: {} -> {}",
+                self.auth_timeout, new.auth_timeout
+            ));
+        }
+        if self.max_auth_failures != new.max_auth_failures {
+            changes.push(format!(
+                "session.max_auth_failures: {} -> {}",
+                self.max_auth_failures, new.max_auth_failures
+            ));
+        }
+        if self.max_unauthenticated_per_ip != new.max_unauthenticated_per_ip {
+            changes.push(format!(
+                "session.max_unauthenticated_per_ip: {} -> {}",
+                self.max_unauthenticated_per_ip, new.max_unauthenticated_per_ip
+            ));
+        }
+        if self.multi_session != new.multi_session {
+            changes.push(format!(
+                "session.multi_session: {:?} -> {:?}",
+                self.multi_session, new.multi_session
+            ));
+        }
+        changes
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self {
+            ping_interval: default_ping_interval(),
+            ping_timeout: default_ping_timeout(),
+            auth_timeout: default_auth_timeout(),
+            max_auth_failures: default_max_auth_failures(),
+            max_unauthenticated_per_ip: default_max_unauthenticated_per_ip(),
+            multi_session: MultiSession::default(),
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct Web {
     bind: String,
     users: Vec<User>,
+    #[serde(default)]
+    trusted_proxies: Vec<IpNet>,
+    #[serde(default = "default_ip_headers")]
+    ip_headers: Vec<IpHeader>,
+    #[serde(default)]
+    proxy_protocol: bool,
+    /// Advertise server on local network through mDNS
+    #[serde(default)]
+    advertise: bool,
 }
 
 impl Web {
@@ -31,20 +292,176 @@ impl Web {
         &self.bind
     }
 
-    pub fn clone_users(&self) -> Vec<String> {
-        self.users.iter().map(|u| u.uuid().to_string()).collect()
+    pub fn has_user(&self, uuid: &str) -> bool {
+        self.users.iter().any(|u| u.uuid().eq(uuid))
+    }
+
+    pub fn has_name(&self, name: &str) -> bool {
+        self.users.iter().any(|u| u.name() == Some(name))
+    }
+
+    /// Find user by name or uuid.
+    pub fn find_user(&self, user: &str) -> Option<&User> {
+        self.users
+            .iter()
+            .find(|u| u.name() == Some(user) || u.uuid().eq(user))
+    }
+
+    pub fn trusted_proxies(&self) -> &[IpNet] {
+        &self.trusted_proxies
+    }
+
+    pub fn ip_headers(&self) -> &[IpHeader] {
+        &self.ip_headers
+    }
+
+    pub fn proxy_protocol(&self) -> bool {
+        self.proxy_protocol
+    }
+
+    pub fn advertise(&self) -> bool {
+        self.advertise
+    }
+
+    /// Port of bind address, checked by `validate`.
+    pub fn port(&self) -> u16 {
+        self.bind
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse().ok())
+            .unwrap_or_default()
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        self.bind
+            .rsplit_once(':')
+            .and_then(|(_, port)| port.parse::<u16>().ok())
+            .ok_or_else(|| anyhow!("Invalid bind address: {:?}", self.bind))?;
+
+        let mut seen = HashSet::new();
+        let mut names = HashSet::new();
+        for user in &self.users {
+            if user.uuid().trim().is_empty() {
+                bail!("User uuid should not be empty");
+            }
+            if !seen.insert(user.uuid()) {
+                bail!("Duplicate user: {}", user.uuid());
+            }
+            if let Some(name) = user.name() {
+                if !names.insert(name) {
+                    bail!("Duplicate user name: {name}");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn diff(&self, new: &Self) -> Vec<String> {
+        let mut changes = vec![];
+        if self.bind != new.bind {
+            changes.push(format!("bind: {} -> {}", self.bind, new.bind));
+        }
+
+        for user in &new.users {
+            match self.users.iter().find(|u| u.uuid().eq(user.uuid())) {
+                None => changes.push(format!("users: added {user}")),
+                Some(old) if old.name() != user.name() => {
+                    changes.push(format!("users: renamed {old} -> {user}"))
+                }
+                _ => {}
+            }
+        }
+        for user in &self.users {
+            if !new.has_user(user.uuid()) {
+                changes.push(format!("users: removed {user}"));
+            }
+        }
+
+        if self.trusted_proxies != new.trusted_proxies {
+            changes.push(format!(
+                "trusted_proxies: {:?} -> {:?}",
+                self.trusted_proxies, new.trusted_proxies
+            ));
+        }
+        if self.ip_headers != new.ip_headers {
+            changes.push(format!(
+                "ip_headers: {:?} -> {:?}",
+                self.ip_headers, new.ip_headers
+            ));
+        }
+        if self.proxy_protocol != new.proxy_protocol {
+            changes.push(format!(
+                "proxy_protocol: {} -> {}",
+                self.proxy_protocol, new.proxy_protocol
+            ));
+        }
+        if self.advertise != new.advertise {
+            changes.push(format!(
+                "advertise: {} -> {}",
+                self.advertise, new.advertise
+            ));
+        }
+        changes
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Admin {
+    token: String,
+}
+
+impl Admin {
+    pub fn token(&self) -> &str {
+        &self.token
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.token.trim().is_empty() {
+            bail!("Admin token should not be empty");
+        }
+        Ok(())
     }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 pub struct User {
     uuid: String,
+    name: Option<String>,
 }
 
 impl User {
     pub fn uuid(&self) -> &str {
         &self.uuid
     }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+}
+
+impl std::fmt::Display for User {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.name {
+            Some(ref name) => write!(f, "{name} ({})", self.uuid),
+            None => write!(f, "{}", self.uuid),
+        }
+    }
+}
+
+/// Headers which may carry the client address when the peer is a trusted proxy.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpHeader {
+    XRealIp,
+    XForwardedFor,
+    Forwarded,
+}
+
+fn default_ip_headers() -> Vec<IpHeader> {
+    vec![
+        IpHeader::XRealIp,
+        IpHeader::XForwardedFor,
+        IpHeader::Forwarded,
+    ]
 }
 
 impl Default for Web {
@@ -52,6 +469,10 @@ impl Default for Web {
         Self {
             bind: "127.0.0.1:37001".to_string(),
             users: vec![],
+            trusted_proxies: vec![],
+            ip_headers: default_ip_headers(),
+            proxy_protocol: false,
+            advertise: false,
         }
     }
 }
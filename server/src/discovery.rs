@@ -0,0 +1,84 @@
+use log::{debug, error, info};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use tap::TapFallible;
+use tokio::sync::watch;
+
+use crate::config::Config;
+
+/// Must match the service type browsed by client.
+const SERVICE_TYPE: &str = "_firendo._tcp.local.";
+
+fn host_name() -> String {
+    std::env::var("HOSTNAME")
+        .or_else(|_| std::env::var("COMPUTERNAME"))
+        .ok()
+        .filter(|name| !name.is_empty())
+        .unwrap_or_else(|| "firendo-host".to_string())
+}
+
+fn register(daemon: &ServiceDaemon, port: u16) -> anyhow::Result<String> {
+    let name = host_name();
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &name,
+        &format!("{name}.local."),
+        (),
+        port,
+        [("path", "/ws/"), ("version", env!("CARGO_PKG_VERSION"))].as_slice(),
+    )?
+    .enable_addr_auto();
+    let fullname = service.get_fullname().to_string();
+    daemon.register(service)?;
+    info!("Advertise {fullname} on port {port}");
+    Ok(fullname)
+}
+
+/// Advertise server through mDNS/DNS-SD while `web.advertise` is enabled, follows configure changes.
+pub async fn advertise(mut config: watch::Receiver<Config>) -> anyhow::Result<()> {
+    let mut daemon: Option<ServiceDaemon> = None;
+    let mut registered: Option<(u16, String)> = None;
+
+    loop {
+        let wanted = {
+            let config = config.borrow_and_update();
+            config.web().advertise().then(|| config.web().port())
+        };
+
+        if registered.as_ref().map(|(port, _)| *port) != wanted {
+            if let (Some(daemon), Some((_, fullname))) = (&daemon, registered.take()) {
+                debug!("Stop advertising {fullname}");
+                daemon
+                    .unregister(&fullname)
+                    .tap_err(|e| {
+                        error!("[Can be safely ignored] Unable unregister {fullname}: {e:?}")
+                    })
+                    .ok();
+            }
+            if let Some(port) = wanted {
+                if daemon.is_none() {
+                    daemon = ServiceDaemon::new()
+                        .tap_err(|e| error!("Unable start mDNS daemon: {e:?}"))
+                        .ok();
+                }
+                if let Some(ref daemon) = daemon {
+                    registered = register(daemon, port)
+                        .tap_err(|e| error!("Unable advertise service: {e:?}"))
+                        .ok()
+                        .map(|fullname| (port, fullname));
+                }
+            }
+        }
+
+        if config.changed().await.is_err() {
+            break;
+        }
+    }
+
+    if let Some(daemon) = daemon {
+        if let Some((_, fullname)) = registered {
+            daemon.unregister(&fullname).ok();
+        }
+        daemon.shutdown().ok();
+    }
+    Ok(())
+}
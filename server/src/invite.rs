@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::anyhow;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tap::TapFallible;
+use tokio::{io::AsyncWriteExt, sync::Mutex};
+
+use crate::{monitor::ScanUpdateHelper, user};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+struct Invite {
+    name: String,
+    expires_at: SystemTime,
+}
+
+/// One-time invite codes, redeemed by new clients for a long-term credential.
+#[derive(Clone, Debug)]
+pub struct Invites {
+    pending: Arc<Mutex<HashMap<String, Invite>>>,
+    file: Arc<str>,
+    state: Arc<Path>,
+    reload: ScanUpdateHelper,
+}
+
+impl Invites {
+    /// Pending invites are kept in `<configure>.invites.json` across restart.
+    pub async fn load(file: &str, reload: ScanUpdateHelper) -> Self {
+        let state: PathBuf = Path::new(file).with_extension("invites.json");
+        let pending: HashMap<String, Invite> = match tokio::fs::read(&state).await {
+            Ok(content) => serde_json::from_slice(&content)
+                .tap_err(|e| warn!("Ignore broken invite state {state:?}: {e:?}"))
+                .unwrap_or_default(),
+            Err(_) => HashMap::new(),
+        };
+        Self {
+            pending: Arc::new(Mutex::new(pending)),
+            file: file.into(),
+            state: state.into(),
+            reload,
+        }
+    }
+
+    /// Write pending invites to disk, remove state file if nothing left.
+    pub async fn persist(&self) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().await;
+        pending.retain(|_, invite| invite.expires_at > SystemTime::now());
+        if pending.is_empty() {
+            if tokio::fs::try_exists(&self.state).await? {
+                tokio::fs::remove_file(&self.state).await?;
+            }
+            return Ok(());
+        }
+        // Codes can be redeemed for a credential, keep them private to the owner
+        let temp = self.state.with_extension("json.tmp");
+        tokio::fs::remove_file(&temp).await.ok();
+        let mut options = tokio::fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        options.mode(0o600);
+        let mut state = options.open(&temp).await?;
+        state.write_all(&serde_json::to_vec(&*pending)?).await?;
+        state.flush().await?;
+        tokio::fs::rename(&temp, &self.state).await?;
+        info!(
+            "Saved {} pending invite(s) to {:?}",
+            pending.len(),
+            self.state
+        );
+        Ok(())
+    }
+
+    pub async fn create(&self, name: String, ttl: Duration) -> (String, SystemTime) {
+        let code = uuid::Uuid::new_v4().simple().to_string();
+        let expires_at = SystemTime::now() + ttl;
+        let mut pending = self.pending.lock().await;
+        pending.retain(|_, invite| invite.expires_at > SystemTime::now());
+        pending.insert(code.clone(), Invite { name, expires_at });
+        (code, expires_at)
+    }
+
+    /// Add user to configure file and return its credential, code is consumed only if
+    /// user is added.
+    pub async fn redeem(&self, code: &str) -> anyhow::Result<String> {
+        // Held until added, so the same code can not be redeemed twice
+        let mut pending = self.pending.lock().await;
+        pending.retain(|_, invite| invite.expires_at > SystemTime::now());
+        let invite = pending
+            .get(code)
+            .ok_or_else(|| anyhow!("Invalid or expired invite code"))?;
+
+        let uuid = user::add(&self.file, &invite.name).await?;
+        let invite = pending.remove(code).unwrap();
+        drop(pending);
+        info!("Invite redeemed, added user {}", invite.name);
+        self.reload.need_update().await;
+        Ok(uuid)
+    }
+}
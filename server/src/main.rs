@@ -1,30 +1,48 @@
 use anyhow::anyhow;
 use clap::arg;
 use config::Config;
-use log::{info, warn};
+use log::{debug, error, info, warn};
 use monitor::{FileWatchDog, ScanUpdateEventReceiver, ScanUpdateHelper};
-use tokio::sync::{broadcast, RwLock};
+use tap::TapFallible;
+use tokio::sync::{broadcast, watch};
 
+mod admin;
 mod config;
+mod discovery;
+mod invite;
 mod monitor;
+mod remote;
 mod route;
+mod session;
 mod types;
-use std::{io::Write, sync::Arc};
+mod user;
+use std::io::Write;
 
 async fn update_config_thread(
-    config: String,
-    users: Arc<RwLock<Vec<String>>>,
+    file: String,
+    config: watch::Sender<Config>,
     mut receiver: ScanUpdateEventReceiver,
 ) -> anyhow::Result<()> {
     while let Some(event) = receiver.recv().await {
         match event {
             monitor::ScanUpdateEvent::NeedUpdate => {
-                let cfg = Config::load(&config)
-                    .await
-                    .map_err(|e| anyhow!("Load configure error: {e:?}"))?;
-                let mut new = cfg.web().clone_users();
-                let mut users = users.write().await;
-                std::mem::swap(&mut *users, &mut new);
+                let cfg = match Config::load(&file).await {
+                    Ok(cfg) => cfg,
+                    Err(e) => {
+                        error!("Reload configure error, keep previous configure: {e:?}");
+                        continue;
+                    }
+                };
+
+                // Diff is only for logging, a field it misses must still be applied
+                let changes = config.borrow().diff(&cfg);
+                if changes.is_empty() {
+                    debug!("Configure reloaded, no known field changed");
+                }
+                for change in &changes {
+                    info!("Configure changed: {change}");
+                }
+                config.send_replace(cfg);
             }
             monitor::ScanUpdateEvent::Exit => break,
         }
@@ -32,6 +50,18 @@ async fn update_config_thread(
     Ok(())
 }
 
+#[cfg(unix)]
+async fn reload_on_hangup(sender: ScanUpdateHelper) -> anyhow::Result<()> {
+    let mut signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+    while signal.recv().await.is_some() {
+        info!("Receive SIGHUP, reload configure");
+        if sender.need_update().await.is_none() {
+            break;
+        }
+    }
+    Ok(())
+}
+
 async fn async_main(config: String) -> anyhow::Result<()> {
     let cfg = Config::load(&config)
         .await
@@ -41,21 +71,34 @@ async fn async_main(config: String) -> anyhow::Result<()> {
 
     let (file_event_sender, file_event_receiver) = ScanUpdateHelper::new(64);
 
-    let users = Arc::new(RwLock::new(cfg.web().clone_users()));
+    let (config_sender, config_receiver) = watch::channel(cfg);
 
-    let watchdog = FileWatchDog::start(config.clone(), file_event_sender.clone());
+    let watchdog = FileWatchDog::start([(config.clone().into(), file_event_sender.clone())]);
+
+    let invites = invite::Invites::load(&config, file_event_sender.clone()).await;
 
     let reload_monitor = tokio::spawn(update_config_thread(
         config,
-        users.clone(),
+        config_sender,
         file_event_receiver,
     ));
 
-    let web = tokio::spawn(route::route(cfg.clone(), sender.clone(), users.clone()));
+    #[cfg(unix)]
+    let hangup = tokio::spawn(reload_on_hangup(file_event_sender.clone()));
+
+    let discovery = tokio::spawn(discovery::advertise(config_receiver.clone()));
+
+    let web = tokio::spawn(route::route(
+        config_receiver,
+        sender.clone(),
+        file_event_sender.clone(),
+        invites.clone(),
+        session::Sessions::default(),
+    ));
 
     tokio::select! {
         ret = async {
-            tokio::signal::ctrl_c().await?;
+            wait_exit_signal().await?;
             info!("Send exit request");
             sender.send(types::WebBroadcastEvent::ServerQuit).ok();
             file_event_sender.exit().await;
@@ -69,8 +112,32 @@ async fn async_main(config: String) -> anyhow::Result<()> {
         }
     }
 
-    watchdog.stop();
+    #[cfg(unix)]
+    hangup.abort();
+    watchdog.stop().await;
     reload_monitor.await??;
+    discovery.await??;
+    invites
+        .persist()
+        .await
+        .tap_err(|e| error!("Unable to save pending invites: {e:?}"))
+        .ok();
+    Ok(())
+}
+
+/// Ctrl-C, or SIGTERM from service manager.
+async fn wait_exit_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            ret = tokio::signal::ctrl_c() => ret?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
     Ok(())
 }
 
@@ -92,15 +159,19 @@ fn main() -> anyhow::Result<()> {
             arg!([CONFIG] "Configure file").default_value("config.toml"),
             arg!(--systemd "Disable time output in log"),
         ])
+        .subcommand(user::command())
         .get_matches();
 
     init_log(matches.get_flag("systemd"));
 
-    tokio::runtime::Builder::new_multi_thread()
+    let config = matches.get_one::<String>("CONFIG").unwrap().to_string();
+    let runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
-        .unwrap()
-        .block_on(async_main(
-            matches.get_one::<String>("CONFIG").unwrap().to_string(),
-        ))
+        .unwrap();
+
+    match matches.subcommand() {
+        Some(("user", matches)) => runtime.block_on(user::handle(&config, matches)),
+        _ => runtime.block_on(async_main(config)),
+    }
 }
@@ -1,10 +1,25 @@
-use std::{path::PathBuf, thread::JoinHandle, time::Duration};
+use std::{
+    collections::HashSet,
+    ffi::OsString,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
 use kstool_helper_generator::Helper;
-use log::{debug, error, warn};
-use notify::{Event, RecursiveMode, Watcher};
+use log::{debug, error, info, warn};
+use notify::{
+    event::{AccessKind, AccessMode, ModifyKind},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
 use tap::{TapFallible, TapOptional};
-use tokio::sync::oneshot;
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+    time::Instant,
+};
+
+const DEBOUNCE: Duration = Duration::from_millis(300);
+const REWATCH_INTERVAL: Duration = Duration::from_secs(5);
 
 #[derive(Clone, Copy, Helper)]
 pub enum ScanUpdateEvent {
@@ -12,106 +27,320 @@ pub enum ScanUpdateEvent {
     Exit,
 }
 
-#[derive(Debug)]
-pub struct FileWatchDog {
-    handler: JoinHandle<Option<()>>,
-    stop_signal_channel: oneshot::Sender<bool>,
+/// Identity of the file behind a path, changes whenever the file is rewritten or replaced.
+#[derive(Clone, Debug, PartialEq)]
+struct Fingerprint {
+    #[cfg(unix)]
+    inode: (u64, u64),
+    len: u64,
+    modified: Option<SystemTime>,
 }
 
-impl FileWatchDog {
-    pub fn file_watching(
-        file: String,
-        stop_signal_channel: oneshot::Receiver<bool>,
-        sender: ScanUpdateHelper,
-    ) -> Option<()> {
-        let mut watcher = notify::recommended_watcher(move |res| match res {
-            Ok(event) => {
-                if Self::decide(event) {
-                    tokio::runtime::Builder::new_current_thread()
-                        .build()
-                        .map(|runtime| runtime.block_on(Self::send_event(sender.clone())))
-                        .tap_err(|e| error!("[Can be safely ignored] Unable create runtime: {e:?}"))
-                        .ok();
-                }
-            }
-            Err(e) => {
-                error!("[Can be safely ignored] Got error while watching file {e:?}")
-            }
+impl Fingerprint {
+    fn of(path: &Path) -> Option<Self> {
+        let metadata = std::fs::metadata(path).ok()?;
+        Some(Self {
+            #[cfg(unix)]
+            inode: {
+                use std::os::unix::fs::MetadataExt;
+                (metadata.dev(), metadata.ino())
+            },
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
         })
-        .tap_err(|e| error!("[Can be safely ignored] Can't start watcher {e:?}"))
-        .ok()?;
+    }
 
-        let path = PathBuf::from(file);
+    #[cfg(unix)]
+    fn same_inode(&self, other: &Self) -> bool {
+        self.inode == other.inode
+    }
 
-        watcher
-            .watch(&path, RecursiveMode::NonRecursive)
-            .tap_err(|e| error!("[Can be safely ignored] Unable to watch file: {e:?}"))
-            .ok()?;
+    #[cfg(not(unix))]
+    fn same_inode(&self, _other: &Self) -> bool {
+        true
+    }
+}
 
-        stop_signal_channel
-            .blocking_recv()
-            .tap_err(|e| {
-                error!("[Can be safely ignored] Got error while poll oneshot event: {e:?}")
-            })
-            .ok();
+/// Directories need to be watched for `path`: its own parent, and the parent of symlink target.
+fn watch_directories(path: &Path) -> HashSet<PathBuf> {
+    let parent = |p: &Path| {
+        let parent = match p.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        std::fs::canonicalize(&parent).unwrap_or(parent)
+    };
+    let mut directories = HashSet::from([parent(path)]);
+    if let Ok(target) = std::fs::canonicalize(path) {
+        directories.insert(parent(&target));
+    }
+    directories
+}
 
-        watcher
-            .unwatch(&path)
-            .tap_err(|e| error!("[Can be safely ignored] Unable to unwatch file: {e:?}"))
-            .ok()?;
+/// A single watched file and the channel to notify when it changes.
+struct Target {
+    path: PathBuf,
+    sender: ScanUpdateHelper,
+    file_names: HashSet<OsString>,
+    directories: HashSet<PathBuf>,
+    fingerprint: Option<Fingerprint>,
+    deadline: Option<Instant>,
+}
 
-        debug!("File watcher exited!");
-        Some(())
+impl Target {
+    fn new(path: PathBuf, sender: ScanUpdateHelper) -> Self {
+        let mut ret = Self {
+            fingerprint: Fingerprint::of(&path),
+            path,
+            sender,
+            file_names: HashSet::new(),
+            directories: HashSet::new(),
+            deadline: None,
+        };
+        ret.resolve();
+        ret
+    }
+
+    /// Re-resolve path, symlink target may have been changed.
+    fn resolve(&mut self) {
+        self.file_names = [
+            Some(self.path.clone()),
+            std::fs::canonicalize(&self.path).ok(),
+        ]
+        .into_iter()
+        .flatten()
+        .filter_map(|p| p.file_name().map(|s| s.to_os_string()))
+        .collect();
+        self.directories = watch_directories(&self.path);
     }
 
-    fn decide(event: Event) -> bool {
-        if let notify::EventKind::Access(notify::event::AccessKind::Close(
-            notify::event::AccessMode::Write,
-        )) = event.kind
-        {
+    /// Whether event may change the file content.
+    fn decide(&self, event: &Event) -> bool {
+        if event.need_rescan() {
             return true;
         }
-        event.need_rescan()
+        match event.kind {
+            EventKind::Access(AccessKind::Close(AccessMode::Write))
+            | EventKind::Modify(ModifyKind::Data(_))
+            | EventKind::Modify(ModifyKind::Any) => event.paths.iter().any(|p| {
+                p.file_name()
+                    .is_some_and(|name| self.file_names.contains(name))
+            }),
+            // Directory structure changed, e.g. editor renamed temp file or symlink swapped
+            EventKind::Create(_)
+            | EventKind::Remove(_)
+            | EventKind::Modify(ModifyKind::Name(_)) => event.paths.iter().any(|p| {
+                p.parent()
+                    .is_some_and(|parent| self.directories.contains(parent))
+            }),
+            _ => false,
+        }
     }
 
-    async fn send_event(sender: ScanUpdateHelper) -> Option<()> {
-        sender.need_update().await.tap_none(|| {
+    /// Check file after debounce, returns whether the file has been replaced by another inode.
+    async fn fire(&mut self) -> bool {
+        self.deadline = None;
+        let current = Fingerprint::of(&self.path);
+        if current == self.fingerprint || current.is_none() {
+            return false;
+        }
+
+        let replaced = matches!(
+            (&self.fingerprint, &current),
+            (Some(old), Some(new)) if !old.same_inode(new)
+        );
+        self.fingerprint = current;
+        self.sender.need_update().await.tap_none(|| {
             error!("[Can be safely ignored] Got error while sending event to update thread")
+        });
+        replaced
+    }
+}
+
+/// All targets share one notify watcher, directories are watched once even if shared.
+struct WatchState {
+    watcher: RecommendedWatcher,
+    targets: Vec<Target>,
+    directories: HashSet<PathBuf>,
+}
+
+impl WatchState {
+    /// Re-resolve all targets and (re)establish watches on their directories.
+    fn rewatch(&mut self, force: bool) {
+        let mut wanted = HashSet::new();
+        for target in &mut self.targets {
+            target.resolve();
+            wanted.extend(target.directories.iter().cloned());
+        }
+
+        let stale = self
+            .directories
+            .iter()
+            .filter(|directory| force || !wanted.contains(*directory))
+            .cloned()
+            .collect::<Vec<_>>();
+        for directory in stale {
+            self.watcher.unwatch(&directory).ok();
+            self.directories.remove(&directory);
+        }
+
+        for directory in wanted {
+            if self.directories.contains(&directory) {
+                continue;
+            }
+            if self
+                .watcher
+                .watch(&directory, RecursiveMode::NonRecursive)
+                .tap_err(|e| {
+                    error!("[Can be safely ignored] Unable to watch directory {directory:?}: {e:?}")
+                })
+                .is_ok()
+            {
+                debug!("Watching directory {directory:?}");
+                self.directories.insert(directory);
+            }
+        }
+    }
+
+    fn unwatch(&mut self) {
+        for directory in self.directories.drain() {
+            self.watcher
+                .unwatch(&directory)
+                .tap_err(|e| {
+                    error!("[Can be safely ignored] Unable to unwatch {directory:?}: {e:?}")
+                })
+                .ok();
+        }
+    }
+
+    /// Some wanted directory is not watched currently.
+    fn incomplete(&self) -> bool {
+        self.targets
+            .iter()
+            .any(|target| !target.directories.is_subset(&self.directories))
+    }
+
+    /// Watched directory itself got removed or watcher lost events.
+    fn lost(&self, event: &Event) -> bool {
+        event.need_rescan()
+            || (matches!(event.kind, EventKind::Remove(_))
+                && event.paths.iter().any(|p| self.directories.contains(p)))
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.targets.iter().filter_map(|t| t.deadline).min()
+    }
+
+    fn handle(&mut self, event: Event) {
+        if self.lost(&event) {
+            warn!("Lost watch on directory, re-establishing");
+            self.rewatch(true);
+        }
+        for target in &mut self.targets {
+            if target.decide(&event) {
+                target.deadline = Some(Instant::now() + DEBOUNCE);
+            }
+        }
+    }
+
+    async fn fire(&mut self) {
+        let now = Instant::now();
+        let mut replaced = false;
+        for target in &mut self.targets {
+            if target.deadline.is_some_and(|deadline| deadline <= now) && target.fire().await {
+                info!("{:?} replaced, re-establishing watch", target.path);
+                replaced = true;
+            }
+        }
+        if replaced {
+            self.rewatch(false);
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FileWatchDog {
+    handler: JoinHandle<()>,
+    stop_signal_channel: oneshot::Sender<()>,
+}
+
+impl FileWatchDog {
+    async fn file_watching(
+        targets: Vec<Target>,
+        mut stop_signal_channel: oneshot::Receiver<()>,
+    ) -> Option<()> {
+        let (event_sender, mut event_receiver) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |res| {
+            event_sender.send(res).ok();
         })
+        .tap_err(|e| error!("[Can be safely ignored] Can't start watcher {e:?}"))
+        .ok()?;
+
+        let mut state = WatchState {
+            watcher,
+            targets,
+            directories: HashSet::new(),
+        };
+        state.rewatch(false);
+
+        let mut rewatch_interval = tokio::time::interval(REWATCH_INTERVAL);
+        rewatch_interval.reset();
+
+        loop {
+            let deadline = state.next_deadline();
+            tokio::select! {
+                _ = &mut stop_signal_channel => break,
+                res = event_receiver.recv() => {
+                    match res {
+                        Some(Ok(event)) => state.handle(event),
+                        Some(Err(e)) => error!("[Can be safely ignored] Got error while watching file {e:?}"),
+                        None => break,
+                    }
+                }
+                _ = tokio::time::sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    state.fire().await;
+                }
+                _ = rewatch_interval.tick(), if state.incomplete() => {
+                    state.rewatch(false);
+                }
+            }
+        }
+
+        state.unwatch();
+
+        debug!("File watcher exited!");
+        Some(())
     }
 
-    pub fn start(path: String, sender: ScanUpdateHelper) -> Self {
+    /// Watch each path, `NeedUpdate` is sent through the paired helper when the file changes.
+    pub fn start(targets: impl IntoIterator<Item = (PathBuf, ScanUpdateHelper)>) -> Self {
         let (stop_signal_channel, receiver) = oneshot::channel();
+        let targets = targets
+            .into_iter()
+            .map(|(path, sender)| Target::new(path, sender))
+            .collect();
         Self {
-            handler: std::thread::spawn(|| Self::file_watching(path, receiver, sender)),
+            handler: tokio::spawn(async move {
+                Self::file_watching(targets, receiver).await;
+            }),
             stop_signal_channel,
         }
     }
 
-    pub fn stop(self) -> Option<()> {
+    pub async fn stop(self) -> Option<()> {
         if !self.handler.is_finished() {
             self.stop_signal_channel
-                .send(true)
-                .tap_err(|e| {
-                    error!(
-            "[Can be safely ignored] Unable send terminate signal to file watcher thread: {e:?}",
-        )
+                .send(())
+                .tap_err(|_| {
+                    error!("[Can be safely ignored] Unable send terminate signal to file watcher")
                 })
                 .ok()?;
-            std::thread::spawn(move || {
-                for _ in 0..5 {
-                    std::thread::sleep(Duration::from_millis(100));
-                    if self.handler.is_finished() {
-                        break;
-                    }
-                }
-                if !self.handler.is_finished() {
-                    warn!("[Can be safely ignored] File watching not finished yet.");
-                }
-            })
-            .join()
-            .unwrap();
         }
-        Some(())
+        tokio::time::timeout(Duration::from_millis(500), self.handler)
+            .await
+            .tap_err(|_| warn!("[Can be safely ignored] File watching not finished yet."))
+            .ok()?
+            .tap_err(|e| error!("[Can be safely ignored] File watcher panicked: {e:?}"))
+            .ok()
     }
 }
@@ -0,0 +1,361 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use anyhow::anyhow;
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+use crate::config::{IpHeader, Web};
+
+const PROXY_V1_PREFIX: &[u8] = b"PROXY";
+const PROXY_V1_MAX_LENGTH: usize = 107;
+const PROXY_V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// Resolve the real client address from the peer address and forwarding headers.
+///
+/// Headers are only honoured when the connection comes from a trusted proxy.
+#[derive(Clone, Debug, Default)]
+pub struct IpResolver {
+    trusted_proxies: Vec<IpNet>,
+    headers: Vec<IpHeader>,
+}
+
+impl IpResolver {
+    pub fn new(web: &Web) -> Self {
+        Self {
+            trusted_proxies: web.trusted_proxies().to_vec(),
+            headers: web.ip_headers().to_vec(),
+        }
+    }
+
+    fn is_trusted(&self, ip: &IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|net| net.contains(ip))
+    }
+
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let peer = peer.to_canonical();
+        if !self.is_trusted(&peer) {
+            return peer;
+        }
+
+        for header in &self.headers {
+            let hops = match header {
+                IpHeader::XRealIp => headers
+                    .get("x-real-ip")
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| vec![v.trim().to_string()]),
+                IpHeader::XForwardedFor => Self::collect(headers, "x-forwarded-for")
+                    .map(|v| v.split(',').map(|s| s.trim().to_string()).collect()),
+                IpHeader::Forwarded => {
+                    Self::collect(headers, "forwarded").map(|v| parse_forwarded(&v))
+                }
+            };
+            if let Some(hops) = hops {
+                return self.walk(peer, hops);
+            }
+        }
+        peer
+    }
+
+    fn collect(headers: &HeaderMap, name: &str) -> Option<String> {
+        let values = headers
+            .get_all(name)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .collect::<Vec<_>>();
+        (!values.is_empty()).then(|| values.join(","))
+    }
+
+    /// Walk the hop list from the nearest proxy backwards, stop at the first untrusted address.
+    fn walk(&self, peer: IpAddr, hops: Vec<String>) -> IpAddr {
+        let mut current = peer;
+        for hop in hops.iter().rev() {
+            if !self.is_trusted(&current) {
+                break;
+            }
+            match parse_ip(hop) {
+                Some(ip) => current = ip.to_canonical(),
+                None => break,
+            }
+        }
+        current
+    }
+}
+
+fn parse_forwarded(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.trim()
+                    .eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            })
+        })
+        .collect()
+}
+
+fn parse_ip(s: &str) -> Option<IpAddr> {
+    if let Ok(ip) = s.parse() {
+        return Some(ip);
+    }
+    if let Ok(addr) = s.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    s.strip_prefix('[')
+        .and_then(|s| s.split_once(']'))
+        .and_then(|(ip, _)| ip.parse().ok())
+}
+
+/// Read a HAProxy PROXY protocol (v1 or v2) header from stream.
+///
+/// Returns the source address announced by proxy, or `None` for `LOCAL` / `UNKNOWN` connections.
+pub async fn read_proxy_header<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> anyhow::Result<Option<SocketAddr>> {
+    let mut prefix = [0u8; 5];
+    stream.read_exact(&mut prefix).await?;
+
+    if prefix.eq(PROXY_V1_PREFIX) {
+        let mut line = prefix.to_vec();
+        while !line.ends_with(b"\r\n") {
+            if line.len() >= PROXY_V1_MAX_LENGTH {
+                return Err(anyhow!("PROXY v1 header too long"));
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_proxy_v1(&line[..line.len() - 2]);
+    }
+
+    if prefix.eq(&PROXY_V2_SIGNATURE[..5]) {
+        let mut rest = [0u8; 11];
+        stream.read_exact(&mut rest).await?;
+        if !rest[..7].eq(&PROXY_V2_SIGNATURE[5..]) {
+            return Err(anyhow!("Invalid PROXY v2 signature"));
+        }
+        let (version_command, family) = (rest[7], rest[8]);
+        let length = u16::from_be_bytes([rest[9], rest[10]]) as usize;
+        let mut payload = vec![0u8; length];
+        stream.read_exact(&mut payload).await?;
+        return parse_proxy_v2(version_command, family, &payload);
+    }
+
+    Err(anyhow!("Missing PROXY protocol header"))
+}
+
+fn parse_proxy_v1(line: &[u8]) -> anyhow::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line)?;
+    let parts = line.split(' ').collect::<Vec<_>>();
+    match parts.get(1).copied() {
+        Some("UNKNOWN") => Ok(None),
+        Some("TCP4") | Some("TCP6") if parts.len() == 6 => {
+            let ip: IpAddr = parts[2].parse()?;
+            let port: u16 = parts[4].parse()?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(anyhow!("Invalid PROXY v1 header: {line:?}")),
+    }
+}
+
+fn parse_proxy_v2(
+    version_command: u8,
+    family: u8,
+    payload: &[u8],
+) -> anyhow::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(anyhow!("Unsupported PROXY version: {version_command:#x}"));
+    }
+    match version_command & 0x0f {
+        // LOCAL, e.g. health check from proxy itself
+        0 => return Ok(None),
+        1 => {}
+        command => return Err(anyhow!("Unsupported PROXY v2 command: {command}")),
+    }
+
+    match family >> 4 {
+        // AF_INET
+        1 if payload.len() >= 12 => {
+            let ip = Ipv4Addr::new(payload[0], payload[1], payload[2], payload[3]);
+            let port = u16::from_be_bytes([payload[8], payload[9]]);
+            Ok(Some(SocketAddr::new(ip.into(), port)))
+        }
+        // AF_INET6
+        2 if payload.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&payload[..16]);
+            let port = u16::from_be_bytes([payload[32], payload[33]]);
+            Ok(Some(SocketAddr::new(Ipv6Addr::from(octets).into(), port)))
+        }
+        // AF_UNSPEC / AF_UNIX
+        0 | 3 => Ok(None),
+        _ => Err(anyhow!("Invalid PROXY v2 address block")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolver(trusted: &[&str], headers: Vec<IpHeader>) -> IpResolver {
+        IpResolver {
+            trusted_proxies: trusted.iter().map(|net| net.parse().unwrap()).collect(),
+            headers,
+        }
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn proxy_v1() {
+        assert_eq!(
+            parse_proxy_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443").unwrap(),
+            Some(addr("192.0.2.1:56324"))
+        );
+        assert_eq!(
+            parse_proxy_v1(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443").unwrap(),
+            Some(addr("[2001:db8::1]:56324"))
+        );
+        assert_eq!(parse_proxy_v1(b"PROXY UNKNOWN").unwrap(), None);
+        assert_eq!(
+            parse_proxy_v1(b"PROXY UNKNOWN ffff::1 ffff::2 1 2").unwrap(),
+            None
+        );
+        assert!(parse_proxy_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324").is_err());
+        assert!(parse_proxy_v1(b"PROXY TCP4 192.0.2.1 198.51.100.1 port 443").is_err());
+        assert!(parse_proxy_v1(b"PROXY UDP4 192.0.2.1 198.51.100.1 56324 443").is_err());
+    }
+
+    #[test]
+    fn proxy_v2() {
+        let ipv4 = [192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb];
+        assert_eq!(
+            parse_proxy_v2(0x21, 0x11, &ipv4).unwrap(),
+            Some(addr("192.0.2.1:56324"))
+        );
+
+        let mut ipv6 = vec![0u8; 36];
+        ipv6[..16].copy_from_slice(&"2001:db8::1".parse::<Ipv6Addr>().unwrap().octets());
+        ipv6[32..34].copy_from_slice(&56324u16.to_be_bytes());
+        assert_eq!(
+            parse_proxy_v2(0x21, 0x21, &ipv6).unwrap(),
+            Some(addr("[2001:db8::1]:56324"))
+        );
+
+        // LOCAL, AF_UNSPEC and AF_UNIX carry no client address
+        assert_eq!(parse_proxy_v2(0x20, 0x11, &ipv4).unwrap(), None);
+        assert_eq!(parse_proxy_v2(0x21, 0x00, &[]).unwrap(), None);
+        assert_eq!(parse_proxy_v2(0x21, 0x31, &[0u8; 216]).unwrap(), None);
+
+        // Truncated address blocks
+        assert!(parse_proxy_v2(0x21, 0x11, &ipv4[..11]).is_err());
+        assert!(parse_proxy_v2(0x21, 0x21, &ipv6[..35]).is_err());
+        assert!(parse_proxy_v2(0x21, 0x21, &ipv4).is_err());
+
+        assert!(parse_proxy_v2(0x11, 0x11, &ipv4).is_err());
+        assert!(parse_proxy_v2(0x22, 0x11, &ipv4).is_err());
+    }
+
+    #[tokio::test]
+    async fn proxy_header() {
+        let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /"[..];
+        assert_eq!(
+            read_proxy_header(&mut stream).await.unwrap(),
+            Some(addr("192.0.2.1:56324"))
+        );
+        assert_eq!(stream, b"GET /");
+
+        let mut header = PROXY_V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0x00, 0x0c]);
+        header.extend([192, 0, 2, 1, 198, 51, 100, 1, 0xdc, 0x04, 0x01, 0xbb]);
+        let mut stream = &header[..];
+        assert_eq!(
+            read_proxy_header(&mut stream).await.unwrap(),
+            Some(addr("192.0.2.1:56324"))
+        );
+
+        // Length says 12 but stream ends early
+        let mut stream = &header[..20];
+        assert!(read_proxy_header(&mut stream).await.is_err());
+
+        let mut stream = &b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443"[..];
+        assert!(read_proxy_header(&mut stream).await.is_err());
+
+        let mut stream = &[b'P'; 200][..];
+        assert!(read_proxy_header(&mut stream).await.is_err());
+
+        let mut stream = &b"GET / HTTP/1.1\r\n"[..];
+        assert!(read_proxy_header(&mut stream).await.is_err());
+    }
+
+    #[test]
+    fn forwarded() {
+        assert_eq!(
+            parse_forwarded(r#"for=192.0.2.43, for="[2001:db8:cafe::17]:4711";proto=https"#),
+            ["192.0.2.43", "[2001:db8:cafe::17]:4711"]
+        );
+        assert_eq!(
+            parse_forwarded("proto=http;For=198.51.100.17;by=203.0.113.60, by=10.0.0.1"),
+            ["198.51.100.17"]
+        );
+        assert_eq!(
+            parse_forwarded(r#"for=_hidden, for="unknown""#),
+            ["_hidden", "unknown"]
+        );
+
+        assert_eq!(
+            parse_ip("[2001:db8:cafe::17]:4711"),
+            Some(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(
+            parse_ip("[2001:db8:cafe::17]"),
+            Some(ip("2001:db8:cafe::17"))
+        );
+        assert_eq!(parse_ip("192.0.2.43:80"), Some(ip("192.0.2.43")));
+        assert_eq!(parse_ip("_hidden"), None);
+    }
+
+    #[test]
+    fn resolve() {
+        let resolver = resolver(
+            &["10.0.0.0/8", "::1/128"],
+            vec![IpHeader::Forwarded, IpHeader::XForwardedFor],
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", "203.0.113.9, 10.0.0.2".parse().unwrap());
+
+        // Untrusted peer, headers ignored
+        assert_eq!(resolver.resolve(ip("192.0.2.1"), &headers), ip("192.0.2.1"));
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), &headers),
+            ip("203.0.113.9")
+        );
+        assert_eq!(
+            resolver.resolve(ip("::ffff:10.0.0.1"), &headers),
+            ip("203.0.113.9")
+        );
+
+        // Spoofed hop before an untrusted one is not believed
+        headers.insert(
+            "x-forwarded-for",
+            "198.51.100.7, 203.0.113.9, 10.0.0.2".parse().unwrap(),
+        );
+        assert_eq!(
+            resolver.resolve(ip("10.0.0.1"), &headers),
+            ip("203.0.113.9")
+        );
+
+        // Forwarded is preferred, stops at obfuscated identifier
+        headers.insert("forwarded", r#"for="[2001:db8::1]:4711""#.parse().unwrap());
+        headers.append("forwarded", "for=_hidden".parse().unwrap());
+        assert_eq!(resolver.resolve(ip("::1"), &headers), ip("::1"));
+        headers.insert("forwarded", r#"for="[2001:db8::1]:4711""#.parse().unwrap());
+        assert_eq!(resolver.resolve(ip("::1"), &headers), ip("2001:db8::1"));
+    }
+}
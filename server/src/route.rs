@@ -1,33 +1,58 @@
-use std::{sync::Arc, time::Duration};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
 
+use anyhow::anyhow;
 use axum::{
+    body::Body,
     extract::{
-        ws::{Message, WebSocket},
-        WebSocketUpgrade,
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        ConnectInfo, Request, WebSocketUpgrade,
     },
+    http::{HeaderMap, StatusCode},
     response::IntoResponse,
-    Extension, Json,
+    Extension, Json, Router,
+};
+use hyper::body::Incoming;
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
 };
-use axum_extra::TypedHeader;
-use log::{error, info, warn};
+use log::{debug, error, info, warn};
 use tap::TapFallible;
 use tokio::{
-    sync::{broadcast, RwLock},
-    time::interval,
+    net::{TcpListener, TcpStream},
+    sync::{broadcast, watch},
+    time::{interval, interval_at, Instant, Interval, MissedTickBehavior},
 };
+use tower::ServiceExt;
 
-use crate::{config::Config, types::WebBroadcastEvent, types::WebData};
+use crate::{
+    admin,
+    config::{Config, MultiSession},
+    invite::Invites,
+    monitor::ScanUpdateHelper,
+    remote::{read_proxy_header, IpResolver},
+    session::{SessionGuard, Sessions},
+    types::WebData,
+    types::{Command, WebBroadcastEvent},
+};
 
-use super::types::RealIP;
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub async fn route(
-    config: Config,
+    mut config: watch::Receiver<Config>,
     broadcast: broadcast::Sender<WebBroadcastEvent>,
-    users: Arc<RwLock<Vec<String>>>,
+    reload: ScanUpdateHelper,
+    invites: Invites,
+    sessions: Sessions,
 ) -> anyhow::Result<()> {
     let inner_broadcast = Arc::new(broadcast.clone());
 
     let router = axum::Router::new()
+        .nest("/admin", admin::router())
         .route("/ws/", axum::routing::get(handle_upgrade))
         .route(
             "/",
@@ -36,70 +61,309 @@ pub async fn route(
             }),
         )
         .layer(Extension(inner_broadcast))
-        .layer(Extension(users));
+        .layer(Extension(config.clone()))
+        .layer(Extension(reload))
+        .layer(Extension(invites))
+        .layer(Extension(sessions.clone()));
 
-    let listener = tokio::net::TcpListener::bind(config.web().bind()).await?;
+    let mut bind = config.borrow_and_update().web().bind().to_string();
+    let mut listener = TcpListener::bind(&bind).await?;
+    let mut watching = true;
 
-    axum::serve(listener, router)
-        .with_graceful_shutdown(async move {
-            let mut recv = broadcast.subscribe();
-            while recv.recv().await.is_ok_and(WebBroadcastEvent::is_not_quit) {}
-            tokio::time::sleep(tokio::time::Duration::from_millis(200)).await;
-        })
-        .await?;
+    let mut recv = broadcast.subscribe();
+    loop {
+        let (stream, peer) = tokio::select! {
+            ret = config.changed(), if watching => {
+                if ret.is_err() {
+                    watching = false;
+                    continue;
+                }
+                let new_bind = config.borrow_and_update().web().bind().to_string();
+                if new_bind != bind {
+                    match TcpListener::bind(&new_bind).await {
+                        Ok(new_listener) => {
+                            info!("Rebind from {bind} to {new_bind}");
+                            listener = new_listener;
+                            bind = new_bind;
+                        }
+                        Err(e) => error!("Unable bind to {new_bind}, keep listening on {bind}: {e:?}"),
+                    }
+                }
+                continue;
+            }
+            conn = listener.accept() => {
+                match conn {
+                    Ok(conn) => conn,
+                    Err(e) => {
+                        error!("Accept connection error: {e:?}");
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                }
+            }
+            _ = wait_quit(&mut recv) => break,
+        };
+
+        let router = router.clone();
+        let proxy_protocol = config.borrow().web().proxy_protocol();
+        tokio::spawn(async move {
+            serve_connection(stream, peer, proxy_protocol, router)
+                .await
+                .tap_err(|e| debug!("Serve connection from {peer} error: {e:?}"))
+                .ok();
+        });
+    }
+
+    drop(listener);
+    sessions.drain();
+    let timeout = config.borrow().shutdown().drain_timeout();
+    if tokio::time::timeout(timeout, sessions.drained())
+        .await
+        .is_err()
+    {
+        warn!(
+            "{} session(s) not closed in {timeout:?}, exit anyway",
+            sessions.list().len()
+        );
+    }
     Ok(())
 }
 
+async fn wait_quit(receiver: &mut broadcast::Receiver<WebBroadcastEvent>) {
+    loop {
+        match receiver.recv().await {
+            Ok(WebBroadcastEvent::ServerQuit) | Err(broadcast::error::RecvError::Closed) => break,
+            _ => {}
+        }
+    }
+}
+
+async fn serve_connection(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    proxy_protocol: bool,
+    router: Router,
+) -> anyhow::Result<()> {
+    let peer = if proxy_protocol {
+        tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_header(&mut stream))
+            .await
+            .map_err(|_| anyhow!("Read PROXY header timeout"))??
+            .unwrap_or(peer)
+    } else {
+        peer
+    };
+
+    let service = hyper::service::service_fn(move |mut request: Request<Incoming>| {
+        request.extensions_mut().insert(ConnectInfo(peer));
+        router.clone().oneshot(request.map(Body::new))
+    });
+
+    Builder::new(TokioExecutor::new())
+        .serve_connection_with_upgrades(TokioIo::new(stream), service)
+        .await
+        .map_err(|e| anyhow!("{e:?}"))
+}
+
 pub async fn handle_upgrade(
     ws: WebSocketUpgrade,
-    TypedHeader(real_ip): TypedHeader<RealIP>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Extension(broadcast): Extension<Arc<broadcast::Sender<WebBroadcastEvent>>>,
-    Extension(auth_db): Extension<Arc<RwLock<Vec<String>>>>,
+    Extension(auth_db): Extension<watch::Receiver<Config>>,
+    Extension(invites): Extension<Invites>,
+    Extension(sessions): Extension<Sessions>,
 ) -> impl IntoResponse {
-    ws.on_upgrade(|socket| async move {
-        let ip = real_ip.into_inner();
+    if sessions.is_draining() {
+        return StatusCode::SERVICE_UNAVAILABLE.into_response();
+    }
+    let ip = IpResolver::new(auth_db.borrow().web()).resolve(peer.ip(), &headers);
+    let limit = auth_db.borrow().session().max_unauthenticated_per_ip();
+    let Some(session) = sessions.register(ip, limit) else {
+        warn!("Reject request from {ip}, too many unauthenticated connections");
+        return StatusCode::TOO_MANY_REQUESTS.into_response();
+    };
+    ws.on_upgrade(move |socket| async move {
         info!("Accept request from {ip:?}");
-        handle_websocket(socket, broadcast.clone(), &ip, auth_db.clone())
-            .await
-            .tap_err(|e| error!("Handle {ip} websocket error: {e:?}"))
-            .ok();
+        handle_websocket(
+            socket,
+            broadcast.clone(),
+            ip,
+            auth_db.clone(),
+            invites,
+            session,
+        )
+        .await
+        .tap_err(|e| error!("Handle {ip} websocket error: {e:?}"))
+        .ok();
     })
 }
 
+/// Broadcast `command` from `uuid`, `target` is resolved from user name or uuid.
+fn relay(
+    broadcast: &broadcast::Sender<WebBroadcastEvent>,
+    config: &watch::Receiver<Config>,
+    command: Command,
+    uuid: &str,
+    session: u64,
+    target: Option<String>,
+) {
+    let target = match target {
+        Some(target) => match config.borrow().web().find_user(&target) {
+            Some(user) => Some(user.uuid().to_string()),
+            None => {
+                warn!(
+                    "Unknown target {target:?} from {uuid}, skip {}",
+                    command.as_str()
+                );
+                return;
+            }
+        },
+        None => None,
+    };
+    broadcast
+        .send(WebBroadcastEvent::Relay {
+            command,
+            uuid: uuid.to_string(),
+            session,
+            target,
+        })
+        .ok();
+}
+
+/// Private close code for a credential that will never be accepted, client stops reconnecting.
+const CREDENTIAL_REJECTED: u16 = 4001;
+/// Private close code for a session replaced by a newer one of the same user, client stops
+/// reconnecting so two machines never keep kicking each other.
+const SIGNED_IN_ELSEWHERE: u16 = 4002;
+/// Private close code for a session refused because the user already has one, client keeps
+/// backing off until the other session ends.
+const ALREADY_SIGNED_IN: u16 = 4003;
+
+/// Ping timer, first tick after one period.
+///
+/// Only polled once authenticated, missed ticks are delayed so a late auth does not
+/// send a burst of pings.
+fn heartbeat_interval(period: Duration) -> Interval {
+    let mut heartbeat = interval_at(Instant::now() + period, period);
+    heartbeat.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    heartbeat
+}
+
+async fn close_with(socket: &mut WebSocket, code: u16, reason: &'static str) {
+    socket
+        .send(Message::Close(Some(CloseFrame {
+            code,
+            reason: reason.into(),
+        })))
+        .await
+        .ok();
+}
+
 pub async fn handle_websocket(
     mut socket: WebSocket,
     broadcast: Arc<broadcast::Sender<WebBroadcastEvent>>,
-    ip: &str,
-    auth_db: Arc<RwLock<Vec<String>>>,
+    ip: IpAddr,
+    mut auth_db: watch::Receiver<Config>,
+    invites: Invites,
+    session: SessionGuard,
 ) -> anyhow::Result<()> {
     let mut interval = interval(Duration::from_secs(30));
     let mut client_uuid: Option<String> = None;
     let mut receiver = broadcast.subscribe();
+    let mut watching = true;
+    let auth_deadline = Instant::now() + auth_db.borrow().session().auth_timeout();
+    let mut auth_failures = 0;
+
+    let mut heartbeat = heartbeat_interval(auth_db.borrow().session().ping_interval());
+    let mut last_seen = Instant::now();
+    let mut ping_sequence = 0u64;
+    let mut ping_sent_at = Instant::now();
 
     interval.reset();
+    auth_db.mark_unchanged();
 
     loop {
         tokio::select! {
+            ret = auth_db.changed(), if watching => {
+                if ret.is_err() {
+                    watching = false;
+                    continue;
+                }
+                let (revoked, ping_interval) = {
+                    let config = auth_db.borrow_and_update();
+                    let revoked = client_uuid
+                        .as_ref()
+                        .is_some_and(|uuid| !config.web().has_user(uuid));
+                    (revoked, config.session().ping_interval())
+                };
+                if ping_interval != heartbeat.period() {
+                    debug!("Ping interval of {ip} changed to {ping_interval:?}");
+                    heartbeat = heartbeat_interval(ping_interval);
+                }
+                if revoked {
+                    info!(
+                        "Revoke session of {} from {ip}, user removed from configure",
+                        client_uuid.as_deref().unwrap_or_default()
+                    );
+                    close_with(&mut socket, CREDENTIAL_REJECTED, "User removed from server configure").await;
+                    info!("Disconnect from: {ip}");
+                    return Ok(());
+                }
+            }
             Ok(event) = receiver.recv() => {
-                if client_uuid.is_none() {
+                if client_uuid.is_none() && event != WebBroadcastEvent::ServerQuit {
                     continue;
                 }
                 match event {
-                    WebBroadcastEvent::RequestTerminate(invoke_uuid) => {
-                        if client_uuid.as_ref().unwrap().eq(&invoke_uuid) {
-                            info!("Skip self send terminate");
+                    WebBroadcastEvent::Relay { command, uuid: invoke_uuid, session: invoke_session, target } => {
+                        if invoke_session == session.id() {
+                            info!("Skip self send {}", command.as_str());
                             continue;
                         }
-                        socket.send(Message::Text(format!("terminate {invoke_uuid}"))).await?;
+                        if target.is_some() && target != client_uuid {
+                            continue;
+                        }
+                        let message = {
+                            let config = auth_db.borrow();
+                            let name = config.web().find_user(&invoke_uuid).and_then(|u| u.name());
+                            command.message(&invoke_uuid, name)
+                        };
+                        socket.send(Message::Text(message)).await?;
+                    }
+                    WebBroadcastEvent::Kick { uuid, keep } => {
+                        if keep == session.id() || client_uuid.as_ref().unwrap().ne(&uuid) {
+                            continue;
+                        }
+                        info!("Close previous session of {uuid} from {ip}, signed in elsewhere");
+                        close_with(&mut socket, SIGNED_IN_ELSEWHERE, "Signed in from another session").await;
+                        info!("Disconnect from: {ip}");
+                        return Ok(());
                     }
                     WebBroadcastEvent::ServerQuit => {
-                        socket.send(Message::Text("close".to_string())).await.ok();
-                        break;
+                        let reconnect_after = auth_db.borrow().shutdown().reconnect_after();
+                        socket
+                            .send(Message::Text(format!("shutdown {reconnect_after}")))
+                            .await
+                            .ok();
+                        if reconnect_after > 0 {
+                            close_with(&mut socket, close_code::RESTART, "Server restarting").await;
+                        } else {
+                            close_with(&mut socket, close_code::AWAY, "Server shutting down").await;
+                        }
+                        info!("Disconnect from: {ip}");
+                        return Ok(());
                     }
                 }
             }
-            Some(message) = socket.recv() => {
-                if let Ok(message) = message {
+            message = socket.recv() => {
+                if let Some(Ok(message)) = message {
+                    last_seen = Instant::now();
+                    if let Message::Pong(ref payload) = message {
+                        if payload.eq(&ping_sequence.to_be_bytes()) {
+                            session.set_rtt(ping_sent_at.elapsed());
+                        }
+                        continue;
+                    }
                     if let Ok(text) = message.to_text() {
                         if text.eq("close") {
                             break;
@@ -108,34 +372,127 @@ pub async fn handle_websocket(
                         if let Ok(data) = WebData::try_from(text) {
                             match data {
                                 WebData::Auth { uuid } => {
-                                    if auth_db.read().await.contains(&uuid) {
+                                    if auth_db.borrow().web().has_user(&uuid) {
+                                        let policy = auth_db.borrow().session().multi_session();
+                                        if !session.authenticate(&uuid, policy == MultiSession::RejectNew) {
+                                            warn!("Reject {uuid} from {ip}, already signed in from another session");
+                                            close_with(&mut socket, ALREADY_SIGNED_IN, "Already signed in from another session").await;
+                                            info!("Disconnect from: {ip}");
+                                            return Ok(());
+                                        }
+                                        if policy == MultiSession::NewestWins {
+                                            broadcast
+                                                .send(WebBroadcastEvent::Kick { uuid: uuid.clone(), keep: session.id() })
+                                                .ok();
+                                        }
                                         client_uuid = Some(uuid);
                                         interval.reset_after(Duration::from_secs(114514));
                                     } else {
                                         warn!("ID: {uuid} not in user list");
+                                        auth_failures += 1;
+                                        if auth_failures >= auth_db.borrow().session().max_auth_failures() {
+                                            warn!("Too many failed authentication from {ip}, disconnect");
+                                            close_with(&mut socket, CREDENTIAL_REJECTED, "Too many failed authentication attempts").await;
+                                            info!("Disconnect from: {ip}");
+                                            return Ok(());
+                                        }
                                     }
                                 },
-                                WebData::RequestTerminate => {
+                                WebData::Redeem { code } => {
+                                    if client_uuid.is_some() {
+                                        continue;
+                                    }
+                                    match invites.redeem(&code).await {
+                                        Ok(uuid) => {
+                                            socket.send(Message::Text(format!("credential {uuid}"))).await?;
+                                            session.authenticate(&uuid, false);
+                                            client_uuid = Some(uuid);
+                                            interval.reset_after(Duration::from_secs(114514));
+                                        }
+                                        Err(e) => {
+                                            warn!("Redeem invite from {ip} failed: {e}");
+                                            close_with(&mut socket, CREDENTIAL_REJECTED, "Invalid or expired invite code").await;
+                                            info!("Disconnect from: {ip}");
+                                            return Ok(());
+                                        }
+                                    }
+                                },
+                                WebData::RequestTerminate { triggered_at, target, lockout } => {
                                     match client_uuid {
                                         Some(ref uuid) => {
-                                            info!("Receive terminate request from {uuid}");
-                                            broadcast
-                                                .send(WebBroadcastEvent::RequestTerminate(uuid.clone()))
-                                                .ok();
+                                            match triggered_at {
+                                                Some(at) => info!("Receive queued terminate request from {uuid}, triggered at {at}"),
+                                                None => info!("Receive terminate request from {uuid}"),
+                                            }
+                                            relay(&broadcast, &auth_db, Command::Terminate { lockout }, uuid, session.id(), target);
                                         },
                                         None => continue,
                                     }
 
                                 },
+                                WebData::Poke { target } => {
+                                    if let Some(ref uuid) = client_uuid {
+                                        info!("Receive poke from {uuid}");
+                                        relay(&broadcast, &auth_db, Command::Poke, uuid, session.id(), target);
+                                    }
+                                },
+                                WebData::Cancel => {
+                                    if let Some(ref uuid) = client_uuid {
+                                        info!("Receive cancel from {uuid}");
+                                        relay(&broadcast, &auth_db, Command::Cancel, uuid, session.id(), None);
+                                    }
+                                },
+                                WebData::AllClear { target } => {
+                                    if let Some(ref uuid) = client_uuid {
+                                        info!("Receive all clear from {uuid}");
+                                        relay(&broadcast, &auth_db, Command::AllClear, uuid, session.id(), target);
+                                    }
+                                },
+                                WebData::TargetStarted | WebData::TargetExited => {
+                                    if let Some(ref uuid) = client_uuid {
+                                        let command = if matches!(data, WebData::TargetStarted) {
+                                            Command::TargetStarted
+                                        } else {
+                                            Command::TargetExited
+                                        };
+                                        info!("Receive {} from {uuid}", command.as_str());
+                                        relay(&broadcast, &auth_db, command, uuid, session.id(), None);
+                                    }
+                                },
                             }
                         }
                     } else {
                         warn!("Skip unreadable bytes: {message:?}");
                     }
                 } else {
+                    info!("Disconnect from: {ip}");
                     return Ok(());
                 }
             }
+            _ = heartbeat.tick(), if client_uuid.is_some() => {
+                let timeout = auth_db.borrow().session().ping_timeout();
+                if last_seen.elapsed() > timeout {
+                    warn!(
+                        "{} from {ip} not response in {timeout:?}, disconnect",
+                        client_uuid.as_deref().unwrap_or_default()
+                    );
+                    break;
+                }
+                ping_sequence += 1;
+                ping_sent_at = Instant::now();
+                socket.send(Message::Ping(ping_sequence.to_be_bytes().to_vec())).await?;
+            }
+            _ = tokio::time::sleep_until(auth_deadline), if client_uuid.is_none() => {
+                warn!("{ip} not authenticated in time, disconnect");
+                if auth_failures > 0 {
+                    // Defaults allow fewer attempts than `max_auth_failures` before the deadline
+                    close_with(&mut socket, CREDENTIAL_REJECTED, "Unknown user").await;
+                } else {
+                    close_with(&mut socket, close_code::POLICY, "Authentication timeout").await;
+                }
+                info!("Disconnect from: {ip}");
+                return Ok(());
+            }
             _ = interval.tick() => {
                 if client_uuid.is_none() {
                     socket.send(Message::Text("auth".to_string())).await?;
@@ -0,0 +1,218 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use serde::Serialize;
+use tokio::sync::Notify;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct SessionInfo {
+    id: u64,
+    ip: IpAddr,
+    uuid: Option<String>,
+    /// Unix timestamp in seconds
+    connected_at: u64,
+    rtt_ms: Option<f64>,
+}
+
+/// Registry of live websocket sessions.
+#[derive(Clone, Debug, Default)]
+pub struct Sessions {
+    inner: Arc<Mutex<HashMap<u64, SessionInfo>>>,
+    next_id: Arc<AtomicU64>,
+    draining: Arc<AtomicBool>,
+    removed: Arc<Notify>,
+}
+
+impl Sessions {
+    /// Register new session, returns `None` if `ip` already has `limit` unauthenticated sessions.
+    pub fn register(&self, ip: IpAddr, limit: usize) -> Option<SessionGuard> {
+        let mut inner = self.inner.lock().unwrap();
+        let unauthenticated = inner
+            .values()
+            .filter(|s| s.ip == ip && s.uuid.is_none())
+            .count();
+        if unauthenticated >= limit {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let info = SessionInfo {
+            id,
+            ip,
+            uuid: None,
+            connected_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            rtt_ms: None,
+        };
+        inner.insert(id, info);
+        Some(SessionGuard {
+            id,
+            sessions: self.clone(),
+        })
+    }
+
+    /// Refuse new sessions from now on.
+    pub fn drain(&self) {
+        self.draining.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Wait until all sessions are gone.
+    pub async fn drained(&self) {
+        loop {
+            let notified = self.removed.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+            if self.inner.lock().unwrap().is_empty() {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let mut sessions = self
+            .inner
+            .lock()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect::<Vec<_>>();
+        sessions.sort_by_key(|s| s.id);
+        sessions
+    }
+
+    fn update(&self, id: u64, f: impl FnOnce(&mut SessionInfo)) {
+        if let Some(info) = self.inner.lock().unwrap().get_mut(&id) {
+            f(info);
+        }
+    }
+}
+
+/// Keep session registered until dropped.
+#[derive(Debug)]
+pub struct SessionGuard {
+    id: u64,
+    sessions: Sessions,
+}
+
+impl SessionGuard {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Bind session to user, fails if `exclusive` and user already has another session.
+    pub fn authenticate(&self, uuid: &str, exclusive: bool) -> bool {
+        let mut inner = self.sessions.inner.lock().unwrap();
+        if exclusive
+            && inner
+                .values()
+                .any(|s| s.id != self.id && s.uuid.as_deref() == Some(uuid))
+        {
+            return false;
+        }
+        if let Some(info) = inner.get_mut(&self.id) {
+            info.uuid = Some(uuid.to_string());
+        }
+        true
+    }
+
+    pub fn set_rtt(&self, rtt: Duration) {
+        self.sessions.update(self.id, |info| {
+            info.rtt_ms = Some(rtt.as_secs_f64() * 1000.0)
+        });
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        self.sessions.inner.lock().unwrap().remove(&self.id);
+        self.sessions.removed.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn register_limits_unauthenticated_per_ip() {
+        let sessions = Sessions::default();
+        let first = sessions.register(ip("192.0.2.1"), 2).unwrap();
+        let second = sessions.register(ip("192.0.2.1"), 2).unwrap();
+        assert_ne!(first.id(), second.id());
+        assert!(sessions.register(ip("192.0.2.1"), 2).is_none());
+        // Other addresses are counted apart
+        let _other = sessions.register(ip("192.0.2.2"), 2).unwrap();
+
+        // Authenticated sessions no longer count
+        first.authenticate("abc", false);
+        let third = sessions.register(ip("192.0.2.1"), 2).unwrap();
+        assert!(sessions.register(ip("192.0.2.1"), 2).is_none());
+
+        // Dropped guard frees its slot
+        drop(third);
+        assert!(sessions.register(ip("192.0.2.1"), 2).is_some());
+        assert_eq!(sessions.list().len(), 3);
+    }
+
+    #[test]
+    fn authenticate_exclusive() {
+        let sessions = Sessions::default();
+        let first = sessions.register(ip("192.0.2.1"), 8).unwrap();
+        let second = sessions.register(ip("192.0.2.2"), 8).unwrap();
+        assert!(first.authenticate("abc", true));
+        // Re-authenticating the same session is not a conflict
+        assert!(first.authenticate("abc", true));
+        assert!(!second.authenticate("abc", true));
+        assert!(second.authenticate("bob", true));
+        assert!(second.authenticate("abc", false));
+
+        let users = sessions
+            .list()
+            .into_iter()
+            .map(|s| s.uuid.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(users, ["abc", "abc"]);
+
+        drop(first);
+        let third = sessions.register(ip("192.0.2.3"), 8).unwrap();
+        assert!(!third.authenticate("abc", true));
+        drop(second);
+        assert!(third.authenticate("abc", true));
+    }
+
+    #[tokio::test]
+    async fn drained_after_last_session() {
+        let sessions = Sessions::default();
+        let session = sessions.register(ip("192.0.2.1"), 1).unwrap();
+        sessions.drain();
+        assert!(sessions.is_draining());
+        let waiter = tokio::spawn({
+            let sessions = sessions.clone();
+            async move { sessions.drained().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(!waiter.is_finished());
+        drop(session);
+        tokio::time::timeout(Duration::from_secs(1), waiter)
+            .await
+            .unwrap()
+            .unwrap();
+    }
+}
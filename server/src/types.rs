@@ -1,60 +1,102 @@
-use axum::http::HeaderValue;
-use axum_extra::headers::{self, Header};
-use once_cell::sync::Lazy;
 use serde::Deserialize;
 
-static HEADER_REAL_IP_NAME: Lazy<axum::http::HeaderName> =
-    Lazy::new(|| "X-Real-IP".parse().unwrap());
-
-pub struct RealIP(String);
-
-impl Header for RealIP {
-    fn name() -> &'static axum::http::HeaderName {
-        &HEADER_REAL_IP_NAME
-    }
+#[derive(Clone, Debug, PartialEq)]
+pub enum WebBroadcastEvent {
+    /// Sent by `uuid` through session `session`, to `target` user or everyone
+    Relay {
+        command: Command,
+        uuid: String,
+        session: u64,
+        target: Option<String>,
+    },
+    /// Close sessions of `uuid` except `keep`
+    Kick {
+        uuid: String,
+        keep: u64,
+    },
+    ServerQuit,
+}
 
-    fn decode<'i, I>(values: &mut I) -> Result<Self, axum_extra::headers::Error>
-    where
-        Self: Sized,
-        I: Iterator<Item = &'i axum::http::HeaderValue>,
-    {
-        let value = values.next().ok_or_else(headers::Error::invalid)?;
-        value
-            .to_str()
-            .map(|s| Self(s.to_string()))
-            .map_err(|_| headers::Error::invalid())
-    }
+/// `session` of relays sent by server itself, e.g. from admin API.
+pub const SERVER_SESSION: u64 = u64::MAX;
 
-    fn encode<E: Extend<axum::http::HeaderValue>>(&self, values: &mut E) {
-        let s =
-            HeaderValue::from_str(&self.0).unwrap_or_else(|_| HeaderValue::from_static("ERROR"));
-        values.extend(std::iter::once(s))
-    }
+/// Requests relayed between users, sent to client as `"{command} {uuid}"`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Command {
+    /// Sent as `"terminate {uuid} {lockout}"` if lockout seconds given
+    Terminate {
+        lockout: Option<u64>,
+    },
+    Poke,
+    Cancel,
+    /// Lift lockout early
+    AllClear,
+    /// Notifications, sent as `"{command} {uuid} {name}"` if user has name
+    TargetStarted,
+    TargetExited,
 }
 
-impl RealIP {
-    pub fn into_inner(self) -> String {
-        self.0
+impl Command {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Terminate { .. } => "terminate",
+            Self::Poke => "poke",
+            Self::Cancel => "cancel",
+            Self::AllClear => "all-clear",
+            Self::TargetStarted => "started",
+            Self::TargetExited => "exited",
+        }
     }
-}
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum WebBroadcastEvent {
-    RequestTerminate(String),
-    ServerQuit,
-}
 
-impl WebBroadcastEvent {
-    pub fn is_not_quit(self) -> bool {
-        !self.eq(&Self::ServerQuit)
+    /// `name` is display name of sender, only used by notifications.
+    pub fn message(&self, uuid: &str, name: Option<&str>) -> String {
+        match (self, name) {
+            (
+                Self::Terminate {
+                    lockout: Some(lockout),
+                },
+                _,
+            ) => format!("{} {uuid} {lockout}", self.as_str()),
+            (Self::TargetStarted | Self::TargetExited, Some(name)) => {
+                format!("{} {uuid} {name}", self.as_str())
+            }
+            _ => format!("{} {uuid}", self.as_str()),
+        }
     }
 }
 
 #[derive(Clone, Debug, Deserialize)]
 #[serde(tag = "type")]
 pub enum WebData {
-    Auth { uuid: String },
-    RequestTerminate,
+    Auth {
+        uuid: String,
+    },
+    Redeem {
+        code: String,
+    },
+    RequestTerminate {
+        /// Unix timestamp when client queued this request while offline
+        #[serde(default)]
+        triggered_at: Option<u64>,
+        /// User name or uuid, everyone if omitted
+        #[serde(default)]
+        target: Option<String>,
+        /// Seconds target keeps being killed on sight, client default if omitted
+        #[serde(default)]
+        lockout: Option<u64>,
+    },
+    Poke {
+        #[serde(default)]
+        target: Option<String>,
+    },
+    Cancel,
+    AllClear {
+        #[serde(default)]
+        target: Option<String>,
+    },
+    /// Sender's client saw target process start, relayed to everyone
+    TargetStarted,
+    TargetExited,
 }
 
 impl TryFrom<&str> for WebData {
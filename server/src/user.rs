@@ -0,0 +1,282 @@
+use anyhow::{anyhow, bail};
+use clap::{arg, ArgMatches, Command};
+use tokio::sync::Mutex;
+use toml_edit::{value, ArrayOfTables, DocumentMut, InlineTable, Item, Table, Value};
+
+use crate::config::Config;
+
+pub fn command() -> Command {
+    Command::new("user")
+        .about("Manage users in configure file")
+        .subcommand_required(true)
+        .subcommands([
+            Command::new("add")
+                .about("Add user with generated credential")
+                .arg(arg!(<NAME> "User name")),
+            Command::new("remove")
+                .about("Remove user")
+                .arg(arg!(<USER> "User name or uuid")),
+            Command::new("list").about("List users"),
+            Command::new("rename").about("Rename user").args(&[
+                arg!(<USER> "User name or uuid"),
+                arg!(<NAME> "New user name"),
+            ]),
+        ])
+}
+
+/// `users` under `[web]` may be written either as inline array or array of tables.
+enum Users<'a> {
+    Inline(&'a mut toml_edit::Array),
+    Tables(&'a mut ArrayOfTables),
+}
+
+impl<'a> Users<'a> {
+    fn from_document(document: &'a mut DocumentMut) -> anyhow::Result<Self> {
+        let web = document
+            .get_mut("web")
+            .and_then(Item::as_table_like_mut)
+            .ok_or_else(|| anyhow!("Missing [web] section"))?;
+        if web.get("users").is_none() {
+            web.insert("users", Item::ArrayOfTables(ArrayOfTables::new()));
+        }
+        match web.get_mut("users").unwrap() {
+            Item::Value(Value::Array(array)) => Ok(Self::Inline(array)),
+            Item::ArrayOfTables(tables) => Ok(Self::Tables(tables)),
+            _ => Err(anyhow!("`users` should be an array")),
+        }
+    }
+
+    fn field(&self, index: usize, key: &str) -> Option<&str> {
+        match self {
+            Self::Inline(array) => array
+                .get(index)
+                .and_then(Value::as_inline_table)
+                .and_then(|t| t.get(key))
+                .and_then(Value::as_str),
+            Self::Tables(tables) => tables
+                .get(index)
+                .and_then(|t| t.get(key))
+                .and_then(Item::as_str),
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Self::Inline(array) => array.len(),
+            Self::Tables(tables) => tables.len(),
+        }
+    }
+
+    fn list(&self) -> Vec<(Option<String>, String)> {
+        (0..self.len())
+            .map(|i| {
+                (
+                    self.field(i, "name").map(ToString::to_string),
+                    self.field(i, "uuid").unwrap_or_default().to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn find(&self, user: &str) -> anyhow::Result<usize> {
+        (0..self.len())
+            .find(|&i| self.field(i, "name") == Some(user) || self.field(i, "uuid") == Some(user))
+            .ok_or_else(|| anyhow!("User {user:?} not found"))
+    }
+
+    fn push(&mut self, name: &str, uuid: &str) {
+        match self {
+            Self::Inline(array) => {
+                let mut table = InlineTable::new();
+                table.insert("name", name.into());
+                table.insert("uuid", uuid.into());
+                // Keep one user per line in multi-line arrays, only the indentation of the
+                // last user is copied, not comments above it
+                let indent = array
+                    .iter()
+                    .last()
+                    .and_then(|v| v.decor().prefix()?.as_str())
+                    .and_then(|prefix| prefix.rsplit_once('\n'))
+                    .map(|(_, indent)| indent.to_string());
+                match indent {
+                    Some(indent) => {
+                        // Line end of the last user, including its comment, is moved after the
+                        // comma added for the new one, the rest stays before the closing bracket
+                        let mut tail = array.trailing().as_str().unwrap_or_default().to_string();
+                        if !array.trailing_comma() {
+                            let last = array.get_mut(array.len() - 1).unwrap().decor_mut();
+                            let suffix = last.suffix().and_then(|s| s.as_str()).unwrap_or_default();
+                            tail.insert_str(0, suffix);
+                            last.set_suffix("");
+                        }
+                        let (comment, trailing) = match tail.split_once('\n') {
+                            Some((comment, rest)) => (comment.to_string(), format!("\n{rest}")),
+                            None => (String::new(), tail.clone()),
+                        };
+                        let mut value = Value::InlineTable(table);
+                        value.decor_mut().set_prefix(format!("{comment}\n{indent}"));
+                        value.decor_mut().set_suffix("");
+                        array.push_formatted(value);
+                        array.set_trailing(trailing);
+                    }
+                    None => array.push(table),
+                }
+            }
+            Self::Tables(tables) => {
+                let mut table = Table::new();
+                table.insert("name", value(name));
+                table.insert("uuid", value(uuid));
+                tables.push(table);
+            }
+        }
+    }
+
+    fn remove(&mut self, index: usize) {
+        match self {
+            Self::Inline(array) => {
+                array.remove(index);
+            }
+            Self::Tables(tables) => tables.remove(index),
+        }
+    }
+
+    fn rename(&mut self, index: usize, name: &str) {
+        match self {
+            Self::Inline(array) => {
+                if let Some(table) = array.get_mut(index).and_then(Value::as_inline_table_mut) {
+                    table.insert("name", name.into());
+                    table.fmt();
+                }
+            }
+            Self::Tables(tables) => {
+                if let Some(table) = tables.get_mut(index) {
+                    table.insert("name", value(name));
+                }
+            }
+        }
+    }
+}
+
+/// Held while configure file is read, edited and written back, so concurrent edits
+/// (e.g. two invites redeemed at once) do not lose each other.
+static EDIT_LOCK: Mutex<()> = Mutex::const_new(());
+
+async fn save(file: &str, document: &DocumentMut) -> anyhow::Result<()> {
+    let output = document.to_string();
+    // Make sure running server can still load it
+    let config: Config = toml::from_str(&output)?;
+    config.validate()?;
+    // Write aside then rename, a crash never leaves a truncated configure
+    let temp = format!("{file}.tmp");
+    tokio::fs::write(&temp, output).await?;
+    if let Ok(metadata) = tokio::fs::metadata(file).await {
+        tokio::fs::set_permissions(&temp, metadata.permissions()).await?;
+    }
+    tokio::fs::rename(&temp, file).await?;
+    Ok(())
+}
+
+/// Add user to configure file, returns the generated credential.
+pub async fn add(file: &str, name: &str) -> anyhow::Result<String> {
+    let _guard = EDIT_LOCK.lock().await;
+    let mut document: DocumentMut = tokio::fs::read_to_string(file).await?.parse()?;
+    let mut users = Users::from_document(&mut document)?;
+    if users.find(name).is_ok() {
+        bail!("User {name:?} already exists");
+    }
+    let uuid = uuid::Uuid::new_v4().to_string();
+    users.push(name, &uuid);
+    save(file, &document).await?;
+    Ok(uuid)
+}
+
+pub async fn handle(file: &str, matches: &ArgMatches) -> anyhow::Result<()> {
+    if let Some(("add", matches)) = matches.subcommand() {
+        let name = matches.get_one::<String>("NAME").unwrap();
+        let uuid = add(file, name).await?;
+        println!("Added {name}, credential: {uuid}");
+        return Ok(());
+    }
+
+    let _guard = EDIT_LOCK.lock().await;
+    let mut document: DocumentMut = tokio::fs::read_to_string(file).await?.parse()?;
+    let mut users = Users::from_document(&mut document)?;
+
+    match matches.subcommand() {
+        Some(("remove", matches)) => {
+            let user = matches.get_one::<String>("USER").unwrap();
+            let index = users.find(user)?;
+            users.remove(index);
+            println!("Removed {user}");
+        }
+        Some(("rename", matches)) => {
+            let user = matches.get_one::<String>("USER").unwrap();
+            let name = matches.get_one::<String>("NAME").unwrap();
+            if users.find(name).is_ok() {
+                bail!("User {name:?} already exists");
+            }
+            let index = users.find(user)?;
+            users.rename(index, name);
+            println!("Renamed {user} to {name}");
+        }
+        Some(("list", _)) => {
+            for (name, uuid) in users.list() {
+                println!("{}\t{uuid}", name.as_deref().unwrap_or("-"));
+            }
+            return Ok(());
+        }
+        _ => unreachable!("subcommand is required"),
+    }
+
+    save(file, &document).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push(input: &str) -> String {
+        let mut document: DocumentMut = input.parse().unwrap();
+        Users::from_document(&mut document)
+            .unwrap()
+            .push("bob", "uuid-bob");
+        document.to_string()
+    }
+
+    #[test]
+    fn push_single_line() {
+        assert_eq!(
+            push("[web]\nusers = [{uuid = \"abc\"}]\n"),
+            "[web]\nusers = [{uuid = \"abc\"}, { name = \"bob\", uuid = \"uuid-bob\" }]\n"
+        );
+        assert_eq!(
+            push("[web]\nusers = []\n"),
+            "[web]\nusers = [{ name = \"bob\", uuid = \"uuid-bob\" }]\n"
+        );
+    }
+
+    #[test]
+    fn push_multi_line() {
+        let input = "[web]\nusers = [\n  # alice, desktop\n  { name = \"alice\", uuid = \"a\" }, # main\n]\n";
+        assert_eq!(
+            push(input),
+            "[web]\nusers = [\n  # alice, desktop\n  { name = \"alice\", uuid = \"a\" }, # main\n  { name = \"bob\", uuid = \"uuid-bob\" },\n]\n"
+        );
+    }
+
+    #[test]
+    fn push_multi_line_without_trailing_comma() {
+        assert_eq!(
+            push("[web]\nusers = [ # alice, desktop\n    { name = \"alice\", uuid = \"a\" }\n]\n"),
+            "[web]\nusers = [ # alice, desktop\n    { name = \"alice\", uuid = \"a\" },\n    { name = \"bob\", uuid = \"uuid-bob\" }\n]\n"
+        );
+    }
+
+    #[test]
+    fn push_tables() {
+        assert_eq!(
+            push("[web]\n\n[[web.users]]\nuuid = \"abc\"\n"),
+            "[web]\n\n[[web.users]]\nuuid = \"abc\"\n\n[[web.users]]\nname = \"bob\"\nuuid = \"uuid-bob\"\n"
+        );
+    }
+}